@@ -0,0 +1,142 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use spin::Mutex;
+
+#[cfg_attr(not(test), global_allocator)]
+static ALLOCATOR: LockedHeap = LockedHeap(Mutex::new(KernelHeap {
+    head : 0x0 as *mut FreeRegion
+}));
+
+const MIN_BLOCK : u64 = size_of::<FreeRegion>() as u64; // every block has to be able to hold a free region
+
+// free memory is kept as an address sorted list of regions so neighbours can be merged on free
+struct FreeRegion {
+    size : u64,
+    next : *mut FreeRegion
+}
+
+/// First fit allocator over the kernel heap region.
+pub struct KernelHeap {
+    head : *mut FreeRegion
+}
+
+unsafe impl Send for KernelHeap {}
+
+fn align_up(addr : u64, align : u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+// size and alignment a layout takes on the heap
+fn block_layout(layout : &Layout) -> (u64, u64) {
+    let align = core::cmp::max(layout.align() as u64, MIN_BLOCK);
+    let size = align_up(core::cmp::max(layout.size() as u64, MIN_BLOCK), MIN_BLOCK);
+    (size, align)
+}
+
+impl KernelHeap {
+    unsafe fn init(&mut self, start : u64, size : u64) {
+        self.head = 0x0 as *mut FreeRegion;
+        self.insert_free(start, size);
+    }
+
+    unsafe fn insert_free(&mut self, addr : u64, size : u64) {
+        let mut prev = 0x0 as *mut FreeRegion;
+        let mut curr = self.head;
+        while !curr.is_null() && (curr as u64) < addr {
+            prev = curr;
+            curr = (*curr).next;
+        }
+
+        let region = addr as *mut FreeRegion;
+        (*region).size = size;
+        (*region).next = curr;
+        if !curr.is_null() && addr + size == curr as u64 {
+            (*region).size += (*curr).size;
+            (*region).next = (*curr).next;
+        }
+
+        if prev.is_null() {
+            self.head = region;
+        } else if prev as u64 + (*prev).size == addr {
+            (*prev).size += (*region).size;
+            (*prev).next = (*region).next;
+        } else {
+            (*prev).next = region;
+        }
+    }
+
+    unsafe fn allocate(&mut self, size : u64, align : u64) -> *mut u8 {
+        let mut prev = 0x0 as *mut FreeRegion;
+        let mut curr = self.head;
+        while !curr.is_null() {
+            let region_start = curr as u64;
+            let region_end = region_start + (*curr).size;
+            let mut alloc_start = align_up(region_start, align);
+            if alloc_start != region_start && alloc_start - region_start < MIN_BLOCK {
+                alloc_start = align_up(region_start + MIN_BLOCK, align);
+            }
+
+            if alloc_start + size <= region_end {
+                if prev.is_null() {
+                    self.head = (*curr).next;
+                } else {
+                    (*prev).next = (*curr).next;
+                }
+                if alloc_start > region_start {
+                    self.insert_free(region_start, alloc_start - region_start);
+                }
+                if alloc_start + size < region_end {
+                    self.insert_free(alloc_start + size, region_end - alloc_start - size);
+                }
+                return alloc_start as *mut u8;
+            }
+
+            prev = curr;
+            curr = (*curr).next;
+        }
+        0x0 as *mut u8
+    }
+
+    /// Number of free bytes left on the heap.
+    pub fn free_bytes(& self) -> u64 {
+        let mut free = 0;
+        let mut curr = self.head;
+        while !curr.is_null() {
+            unsafe {
+                free += (*curr).size;
+                curr = (*curr).next;
+            }
+        }
+        free
+    }
+}
+
+pub struct LockedHeap(Mutex<KernelHeap>);
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(&layout);
+        self.0.lock().allocate(size, align)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _align) = block_layout(&layout);
+        self.0.lock().insert_free(ptr as u64, size);
+    }
+}
+
+/// Sets up the kernel heap on contiguous frames from the system frame pool.
+pub fn init_heap() {
+    let frames = crate::machine::KERNEL_HEAP_SIZE / crate::machine::PAGE_SIZE;
+    let heap_start = crate::memory::get_frames(true, false, frames, crate::machine::PAGE_SIZE)
+        .expect("no frames left for the kernel heap")
+        .start_address()
+        .as_u64();
+    unsafe {
+        ALLOCATOR.0.lock().init(heap_start, crate::machine::KERNEL_HEAP_SIZE);
+    }
+}
+
+pub fn heap_free_bytes() -> u64 {
+    ALLOCATOR.0.lock().free_bytes()
+}
@@ -0,0 +1,67 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(kernel_main);
+
+const HUGE_PAGE_SIZE : u64 = 0x20_0000; // 2MB
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    // aligned user frames
+    let frame = blog_os::memory::get_frames(false, true, 4, HUGE_PAGE_SIZE).unwrap();
+    let addr = frame.start_address().as_u64();
+    if addr % HUGE_PAGE_SIZE != 0 {
+        panic!("frames not aligned: {:x}", addr);
+    }
+
+    // freed frames are handed out again as a unit
+    blog_os::memory::free_frames(frame, 4);
+    let frame = blog_os::memory::get_frames(false, true, 4, HUGE_PAGE_SIZE).unwrap();
+    if frame.start_address().as_u64() != addr {
+        panic!("frames not reused: {:x}", frame.start_address().as_u64());
+    }
+    blog_os::memory::free_frames(frame, 4);
+
+    // contiguous kernel frames are usable as one buffer
+    let frame = blog_os::memory::get_frames(true, false, 3, blog_os::machine::PAGE_SIZE).unwrap();
+    let test_mem : &mut [u64; 1536] = unsafe {&mut *(frame.start_address().as_u64() as *mut [u64; 1536])};
+
+    for i in 0..1536 {
+        test_mem[i] = i as u64;
+    }
+
+    for i in 0..1536 {
+        if test_mem[i] != i as u64 {
+            panic!();
+        }
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
@@ -0,0 +1,163 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::{self, MyProcess, ProcessBuilder, SpawnError};
+use blog_os::elf::ElfError;
+use blog_os::shared_memory;
+use blog_os::vm_pool::Protection;
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+const CODE_ADDR : u64 = 0x4000_0000;
+const DATA_ADDR : u64 = 0x4000_1000;
+const BSS_ADDR : u64 = 0x4000_1ff8; // the last qword of the data segment, past its file contents
+const SHARED_ADDR : u64 = 0x4010_0000; // where the program writes its result
+
+// mov rax, [DATA_ADDR]; add rax, [BSS_ADDR]; mov [rdi], rax; mov qword [BSS_ADDR], 5; ret
+const CODE : [u8; 32] = [
+    0x48, 0x8b, 0x04, 0x25, 0x00, 0x10, 0x00, 0x40,
+    0x48, 0x03, 0x04, 0x25, 0xf8, 0x1f, 0x00, 0x40,
+    0x48, 0x89, 0x07,
+    0x48, 0xc7, 0x04, 0x25, 0xf8, 0x1f, 0x00, 0x40, 0x05, 0x00, 0x00, 0x00,
+    0xc3
+];
+const DATA : u64 = 0x1234_5678;
+
+static mut IMAGE : [u8; 0x200] = [0; 0x200];
+
+fn put(image : &mut [u8], at : usize, value : u64, bytes : usize) {
+    for i in 0..bytes {
+        image[at + i] = (value >> (8 * i)) as u8;
+    }
+}
+
+// an executable with a read/execute code segment and a read/write data segment with a bss
+fn build_image(image : &mut [u8]) {
+    image[0..4].copy_from_slice(&[0x7f, b'E', b'L', b'F']);
+    image[4] = 2; // 64 bit
+    image[5] = 1; // little endian
+    image[6] = 1; // version
+    put(image, 16, 2, 2); // executable
+    put(image, 18, 0x3e, 2); // x86_64
+    put(image, 20, 1, 4);
+    put(image, 24, CODE_ADDR, 8); // entry
+    put(image, 32, 64, 8); // program headers right after this header
+    put(image, 52, 64, 2);
+    put(image, 54, 56, 2);
+    put(image, 56, 2, 2);
+
+    let segments = [(5, 0x100, CODE_ADDR, CODE.len() as u64, CODE.len() as u64),
+                    (6, 0x180, DATA_ADDR, 8, 0x1000)];
+    for (i, &(flags, offset, vaddr, file_size, mem_size)) in segments.iter().enumerate() {
+        let at = 64 + i * 56;
+        put(image, at, 1, 4); // PT_LOAD
+        put(image, at + 4, flags, 4);
+        put(image, at + 8, offset, 8);
+        put(image, at + 16, vaddr, 8);
+        put(image, at + 24, vaddr, 8);
+        put(image, at + 32, file_size, 8);
+        put(image, at + 40, mem_size, 8);
+        put(image, at + 48, 0x1000, 8);
+    }
+
+    image[0x100..0x100 + CODE.len()].copy_from_slice(&CODE);
+    put(image, 0x180, DATA, 8);
+}
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let image = unsafe { &mut IMAGE };
+    build_image(image);
+
+    let mut bad_image = [0u8; 0x200];
+    bad_image.copy_from_slice(image);
+    bad_image[0] = 0;
+    if ProcessBuilder::program().spawn_elf(&bad_image).err() != Some(SpawnError::Elf(ElfError::BadMagic)) {
+        panic!("bad magic accepted");
+    }
+    bad_image.copy_from_slice(image);
+    put(&mut bad_image, 64 + 8, 0x1000, 8); // code past the end of the image
+    if ProcessBuilder::program().spawn_elf(&bad_image).err() != Some(SpawnError::Elf(ElfError::Truncated)) {
+        panic!("truncated image accepted");
+    }
+    bad_image.copy_from_slice(image);
+    put(&mut bad_image, 32, u64::max_value() - 8, 8); // program headers that wrap around
+    if ProcessBuilder::program().spawn_elf(&bad_image).err() != Some(SpawnError::Elf(ElfError::Truncated)) {
+        panic!("wrapping program header offset accepted");
+    }
+    bad_image.copy_from_slice(image);
+    put(&mut bad_image, 64 + 56 + 16, CODE_ADDR + 0x800, 8); // data in the code segment's page
+    if ProcessBuilder::program().spawn_elf(&bad_image).err() != Some(SpawnError::Elf(ElfError::BadSegment)) {
+        panic!("segments sharing a page accepted");
+    }
+
+    let parent = MyProcess::new(parent as blog_os::machine::CFunc);
+    process_table::set_next_process(parent);
+    process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn parent() {
+    // the program runs in ring 3, it can only hand back its result through memory of its own
+    let handle = shared_memory::create(blog_os::machine::PAGE_SIZE).unwrap();
+    let result = process_table::get_curr_process_table_mut().vm_pool
+        .map_shared(handle, Protection::READ | Protection::WRITE).unwrap().as_u64() as *const u64;
+
+    let program = ProcessBuilder::program()
+        .args(&[SHARED_ADDR])
+        .name("program")
+        .spawn_elf(unsafe { &IMAGE })
+        .unwrap();
+    let pid = program.process_id;
+    if program.vm_pool.map_shared_at(VirtAddr::new(SHARED_ADDR), handle, Protection::READ | Protection::WRITE)
+        != Ok(VirtAddr::new(SHARED_ADDR)) {
+        panic!("shared memory not mapped at {:#x}", SHARED_ADDR);
+    }
+
+    // the code segment must not be writable
+    let code_prot = program.vm_pool.get_protection(VirtAddr::new(CODE_ADDR)).unwrap();
+    if code_prot.contains(Protection::WRITE) || !code_prot.contains(Protection::EXECUTE) {
+        panic!("code segment is {:?}", code_prot);
+    }
+
+    blog_os::scheduler::resume(program);
+    if process_table::wait(pid) != Some(0) {
+        panic!("program did not exit");
+    }
+
+    // the data came from the image and the bss was zero
+    if unsafe { *result } != DATA {
+        panic!("program computed {:#x}", unsafe { *result });
+    }
+    shared_memory::close(handle);
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
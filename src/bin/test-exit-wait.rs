@@ -0,0 +1,71 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::{self, MyProcess, ProcessBuilder};
+
+entry_point!(kernel_main);
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let parent = MyProcess::new(parent as blog_os::machine::CFunc);
+    process_table::set_next_process(parent);
+    process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn parent() {
+    let exiting = ProcessBuilder::with_arg(exiting, 42).spawn().unwrap();
+    let returning = MyProcess::new(returning as blog_os::machine::CFunc);
+    let (exiting_pid, returning_pid) = (exiting.process_id, returning.process_id);
+    blog_os::scheduler::resume(exiting);
+    blog_os::scheduler::resume(returning);
+
+    let code = process_table::wait(exiting_pid).unwrap();
+    if code != 42 {
+        panic!("exit code {}", code);
+    }
+    let code = process_table::wait(returning_pid).unwrap();
+    if code != 0 {
+        panic!("exit code {} for a process that returned", code);
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn exiting(code : u64) {
+    // still running after the parent started waiting
+    for _i in 0..3 {
+        blog_os::scheduler::_yield();
+    }
+    process_table::exit(code);
+}
+
+extern "C" fn returning() {
+}
@@ -0,0 +1,81 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::MyProcess;
+
+entry_point!(kernel_main);
+
+static mut CHILD_DONE : bool = false;
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let process = MyProcess::new(process_function as blog_os::machine::CFunc);
+    blog_os::process_table::set_next_process(process);
+    blog_os::process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn process_function() {
+    let vm_pool = &mut blog_os::process_table::get_curr_process_table_mut().vm_pool;
+    let addr = vm_pool.allocate(blog_os::machine::PAGE_SIZE as usize).unwrap();
+    let value = addr.as_u64() as *mut u64;
+    unsafe { *value = 42; }
+
+    let pid = blog_os::process_table::fork();
+    if pid == 0 {
+        // the child sees the parent's memory and gets its own copy once it writes
+        unsafe {
+            if *value != 42 {
+                panic!("child read {}", *value);
+            }
+            *value = 7;
+            CHILD_DONE = true;
+        }
+        loop {
+            blog_os::scheduler::_yield();
+        }
+    }
+
+    while unsafe { !CHILD_DONE } {
+        blog_os::scheduler::_yield();
+    }
+    unsafe {
+        if *value != 42 {
+            panic!("the child's write reached the parent: {}", *value);
+        }
+        // the child holds its own copy now, so the parent writes to the original frame
+        *value = 5;
+        if *value != 5 {
+            panic!();
+        }
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
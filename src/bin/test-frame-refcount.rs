@@ -0,0 +1,81 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::memory;
+
+entry_point!(kernel_main);
+
+fn free_user_frames() -> u64 {
+    memory::stats().user.free_frames
+}
+
+// only the panic of the last step may end the test
+static mut FREEING_TWICE : bool = false;
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    memory::init_frame_allocator(&boot_info.memory_map);
+
+    let free_before = free_user_frames();
+
+    // a shared frame survives until the last reference is dropped
+    let frame = memory::get_frame(false, false).unwrap();
+    if memory::frame_ref_count(frame) != 1 || memory::get_frame_ref(frame) != 2 {
+        panic!();
+    }
+    memory::free_frame(frame);
+    if memory::frame_ref_count(frame) != 1 || free_user_frames() != free_before - 1 {
+        panic!("frame released with references left");
+    }
+    if memory::put_frame_ref(frame) != 0 || free_user_frames() != free_before {
+        panic!("frame not released");
+    }
+
+    // frames of a contiguous block are counted one by one
+    let frames = memory::get_frames(false, true, 4, blog_os::machine::PAGE_SIZE).unwrap();
+    let second = frames + 1;
+    memory::get_frame_ref(second);
+    memory::free_frames(frames, 4);
+    if free_user_frames() != free_before - 1 || memory::frame_ref_count(second) != 1 {
+        panic!("shared frame of the block was released");
+    }
+    memory::free_frame(second);
+    if free_user_frames() != free_before {
+        panic!("block not released");
+    }
+
+    // a frame nobody holds can't go back to the pool again
+    unsafe {
+        FREEING_TWICE = true;
+    }
+    memory::free_frame(second); // this should panic
+
+    serial_println!("failed");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if unsafe { FREEING_TWICE } {
+        serial_println!("ok");
+    } else {
+        serial_println!("failed");
+        serial_println!("{}", info);
+    }
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
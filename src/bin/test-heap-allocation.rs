@@ -0,0 +1,77 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+#![feature(alloc)]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec, collections::BTreeMap};
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(kernel_main);
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let free_before = blog_os::allocator::heap_free_bytes();
+
+    {
+        let boxed = Box::new(41);
+        if *boxed + 1 != 42 {
+            panic!();
+        }
+
+        let mut vec = Vec::new();
+        for i in 0..1000 {
+            vec.push(i as u64);
+        }
+        if vec.iter().sum::<u64>() != 999 * 1000 / 2 {
+            panic!();
+        }
+
+        let mut map = BTreeMap::new();
+        for i in 0..100 {
+            map.insert(i, i * 2);
+        }
+        if map[&50] != 100 {
+            panic!();
+        }
+    }
+
+    // allocates far more than the heap size in total, which only works if memory is reused
+    for i in 0..(blog_os::machine::KERNEL_HEAP_SIZE / 8) {
+        let boxed = Box::new([i; 16]);
+        if boxed[15] != i {
+            panic!();
+        }
+    }
+
+    if blog_os::allocator::heap_free_bytes() != free_before {
+        panic!("heap leaked {} bytes", free_before - blog_os::allocator::heap_free_bytes());
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
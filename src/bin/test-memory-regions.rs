@@ -0,0 +1,69 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::{BootInfo, MemoryRegionType}, entry_point};
+use core::panic::PanicInfo;
+
+entry_point!(kernel_main);
+
+// Every usable frame above KERNEL_SPACE has to be reachable through the user pools,
+// however much memory qemu is started with (try -m 2G).
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let mut usable_frames = 0;
+    for region in boot_info.memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable) {
+        let start = core::cmp::max(region.range.start_addr(), blog_os::machine::KERNEL_SPACE);
+        if start < region.range.end_addr() {
+            usable_frames += (region.range.end_addr() - start) / blog_os::machine::PAGE_SIZE;
+        }
+    }
+
+    // the user pools span all of it, only their page info arrays are held back
+    let user_stats = blog_os::memory::stats().user;
+    if user_stats.total_frames != usable_frames {
+        panic!("user pools span {} of {} usable frames", user_stats.total_frames, usable_frames);
+    }
+
+    let mut allocated_frames = 0;
+    while let Some(frame) = blog_os::memory::get_frame(false, false) {
+        // the frame has to be reachable through the direct map as well
+        let test_mem = blog_os::memory::phys_to_virt(frame.start_address()).as_u64() as *mut u64;
+        unsafe {
+            *test_mem = frame.start_address().as_u64();
+            if *test_mem != frame.start_address().as_u64() {
+                panic!();
+            }
+        }
+        allocated_frames += 1;
+    }
+
+    if allocated_frames != user_stats.free_frames {
+        panic!("allocated {} of {} free frames", allocated_frames, user_stats.free_frames);
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::MyProcess;
+use blog_os::memory::PoolStats;
+
+entry_point!(kernel_main);
+
+fn check_consistent(stats : &PoolStats) {
+    if stats.free_frames + stats.used_frames + stats.reserved_frames != stats.total_frames {
+        panic!("inconsistent stats {:?}", stats);
+    }
+}
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let mut page_table : &'static mut MyProcess = MyProcess::new(process_function as blog_os::machine::CFunc);
+    page_table = page_table.load_page_table();
+
+    let before = blog_os::memory::stats();
+    check_consistent(&before.system);
+    check_consistent(&before.user);
+
+    let x = &mut page_table.vm_pool;
+    let addr = x.allocate(core::mem::size_of::<[u64; 10000]>()).unwrap();
+    let test_mem : &mut [u64; 10000] = unsafe {&mut *(addr.as_u64() as *mut [u64; 10000])};
+    for i in 0..10000 {
+        test_mem[i] = i as u64;
+    }
+
+    let during = blog_os::memory::stats();
+    check_consistent(&during.user);
+    if during.user.used_frames != before.user.used_frames + 20 {
+        panic!("expected 20 more used frames: {:?}", during.user);
+    }
+
+    x.release(addr);
+
+    let after = blog_os::memory::stats();
+    check_consistent(&after.user);
+    if after.user.free_frames != before.user.free_frames {
+        panic!("release leaked {} frames", before.user.free_frames - after.user.free_frames);
+    }
+    if after.user.deallocations != before.user.deallocations + 20 {
+        panic!("expected 20 more deallocations: {:?}", after.user);
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn process_function() {
+    serial_println!("process function");
+}
@@ -0,0 +1,72 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::ProcessBuilder;
+
+entry_point!(kernel_main);
+
+struct Job {
+    values : [u64; 4],
+    sum : u64
+}
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    // the job lives in kernel memory, which every process maps
+    static mut JOB : Job = Job { values : [1, 2, 3, 4], sum : 10 };
+
+    let process = ProcessBuilder::with_args(worker, &[unsafe { &JOB as *const Job as u64 }, 7, 11])
+        .name("worker")
+        .priority(3)
+        .stack_size(4 * blog_os::machine::PAGE_SIZE)
+        .spawn().unwrap();
+    if process.name() != "worker" || process.priority() != 3 {
+        panic!("name or priority not set");
+    }
+
+    blog_os::process_table::set_next_process(process);
+    blog_os::process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn worker(job : u64, a : u64, b : u64, unused : u64, _e : u64, _f : u64) {
+    let job = unsafe { &*(job as *const Job) };
+    if job.values.iter().sum::<u64>() != job.sum {
+        panic!("argument pointer not passed");
+    }
+    if a != 7 || b != 11 || unused != 0 {
+        panic!("register arguments {} {}", a, b);
+    }
+    if blog_os::process_table::get_curr_process_table().name() != "worker" {
+        panic!();
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
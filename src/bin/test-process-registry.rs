@@ -0,0 +1,113 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::{self, MyProcess};
+
+entry_point!(kernel_main);
+
+static mut STOP : bool = false;
+static mut LONG_LIVED_PID : u16 = 0;
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let parent = MyProcess::new(parent as blog_os::machine::CFunc);
+    process_table::set_next_process(parent);
+    process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn parent() {
+    let this = process_table::get_curr_process_table();
+
+    // a child that lives through all the others
+    let long_lived = MyProcess::new(long_lived as blog_os::machine::CFunc);
+    let long_lived_pid = long_lived.process_id;
+    blog_os::scheduler::resume(long_lived);
+    if long_lived.parent_id() != this.process_id || this.children().count() != 1 {
+        panic!("child not linked to its parent");
+    }
+    match process_table::get_process(long_lived_pid) {
+        Some(process) if process.process_id == long_lived_pid => {},
+        _ => panic!("lookup failed")
+    }
+
+    // only the parent collects a process
+    unsafe { LONG_LIVED_PID = long_lived_pid; }
+    let sibling = MyProcess::new(sibling as blog_os::machine::CFunc);
+    let sibling_pid = sibling.process_id;
+    blog_os::scheduler::resume(sibling);
+    if process_table::wait(sibling_pid) != Some(0) || process_table::get_process(long_lived_pid).is_none() {
+        panic!("a sibling collected the long lived process");
+    }
+
+    // more processes than there are ids, so ids have to be reused, and never a live one
+    for _i in 0..(u16::max_value() as u32 + 16) {
+        let child = MyProcess::new(short_lived as blog_os::machine::CFunc);
+        let pid = child.process_id;
+        if pid == long_lived_pid || pid == this.process_id {
+            panic!("id {} handed out twice", pid);
+        }
+        blog_os::scheduler::resume(child);
+        process_table::wait(pid).unwrap();
+        if process_table::get_process(pid).is_some() {
+            panic!("collected process still registered");
+        }
+    }
+
+    if process_table::processes().count() != 2 || process_table::wait(0).is_some() {
+        panic!("unexpected processes");
+    }
+
+    unsafe { STOP = true; }
+    process_table::wait(long_lived_pid).unwrap();
+    if this.children().count() != 0 {
+        panic!();
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn long_lived() {
+    while unsafe { !STOP } {
+        blog_os::scheduler::_yield();
+    }
+}
+
+extern "C" fn short_lived() {
+}
+
+// exits with 1 if it could wait for a process that isn't its child
+extern "C" fn sibling() {
+    let code = match process_table::wait(unsafe { LONG_LIVED_PID }) {
+        Some(_) => 1,
+        None => 0
+    };
+    process_table::exit(code);
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::{self, MyProcess, ProcessBuilder, ProcessState, BlockReason};
+
+entry_point!(kernel_main);
+
+// only resuming a running process may end the test
+static mut RESUMING_RUNNING : bool = false;
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let parent = MyProcess::new(parent as blog_os::machine::CFunc);
+    process_table::set_next_process(parent);
+    process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if unsafe { RESUMING_RUNNING } {
+        serial_println!("ok");
+    } else {
+        serial_println!("failed");
+        serial_println!("{}", info);
+    }
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn parent() {
+    let this = process_table::get_curr_process_table_mut();
+    if this.state() != ProcessState::Running {
+        panic!("parent is {:?}", this.state());
+    }
+
+    let child = ProcessBuilder::with_arg(child_function, this as *mut MyProcess as u64).spawn().unwrap();
+    if child.state() != ProcessState::New {
+        panic!("new process is {:?}", child.state());
+    }
+    blog_os::scheduler::resume(child);
+    if child.state() != ProcessState::Ready {
+        panic!("queued process is {:?}", child.state());
+    }
+    process_table::wait(child.process_id);
+
+    if ProcessState::Zombie.can_become(ProcessState::Ready) || ProcessState::New.can_become(ProcessState::Zombie) {
+        panic!("invalid transitions allowed");
+    }
+
+    unsafe { RESUMING_RUNNING = true; }
+    blog_os::scheduler::resume(this); // this should panic
+
+    unsafe { RESUMING_RUNNING = false; }
+    panic!("a running process was resumed");
+}
+
+extern "C" fn child_function(parent : u64) {
+    let parent = unsafe { &*(parent as *const MyProcess) };
+    let this = process_table::get_curr_process_table();
+    if this.state() != ProcessState::Running {
+        panic!("child is {:?}", this.state());
+    }
+    if parent.state() != ProcessState::Blocked(BlockReason::Wait(this.process_id)) {
+        panic!("waiting parent is {:?}", parent.state());
+    }
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::MyProcess;
+use blog_os::memory::MemoryStats;
+
+entry_point!(kernel_main);
+
+static mut BEFORE : Option<MemoryStats> = None;
+static mut HEAP_BEFORE : u64 = 0;
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    // the checker runs once the worker is gone
+    let checker = MyProcess::new(checker as blog_os::machine::CFunc);
+    blog_os::scheduler::resume(checker);
+    unsafe {
+        BEFORE = Some(blog_os::memory::stats());
+        HEAP_BEFORE = blog_os::allocator::heap_free_bytes();
+    }
+
+    let worker = MyProcess::new(worker as blog_os::machine::CFunc);
+    blog_os::process_table::set_next_process(worker);
+    blog_os::process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+// leaves pages mapped all over its VMPool
+extern "C" fn worker() {
+    let vm_pool = &mut blog_os::process_table::get_curr_process_table_mut().vm_pool;
+    for i in 0..10 {
+        let addr = vm_pool.allocate(0o4_000_000).unwrap(); // 1MB
+        unsafe { *(addr.as_u64() as *mut u64) = i; }
+    }
+}
+
+extern "C" fn checker() {
+    let before = unsafe { BEFORE.unwrap() };
+    let after = blog_os::memory::stats();
+    if after.user.free_frames != before.user.free_frames {
+        panic!("{} user frames leaked", before.user.free_frames as i64 - after.user.free_frames as i64);
+    }
+    if after.system.free_frames != before.system.free_frames {
+        panic!("{} system frames leaked", before.system.free_frames as i64 - after.system.free_frames as i64);
+    }
+    if blog_os::allocator::heap_free_bytes() != unsafe { HEAP_BEFORE } {
+        panic!("heap memory leaked");
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
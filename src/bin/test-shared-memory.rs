@@ -0,0 +1,104 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::MyProcess;
+use blog_os::shared_memory::{self, SharedHandle};
+use blog_os::vm_pool::Protection;
+use blog_os::machine::PAGE_SIZE;
+
+entry_point!(kernel_main);
+
+static mut HANDLE : Option<SharedHandle> = None;
+static mut CONSUMED : bool = false;
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    // sizes that overflow or that no VMPool could map are refused
+    if shared_memory::create(u64::max_value()).is_some()
+        || shared_memory::create(blog_os::machine::HEAP_SIZE + PAGE_SIZE).is_some() {
+        panic!("created an object too large to map");
+    }
+    unsafe { HANDLE = shared_memory::create(2 * PAGE_SIZE); }
+
+    let producer = MyProcess::new(producer as blog_os::machine::CFunc);
+    let consumer = MyProcess::new(consumer as blog_os::machine::CFunc);
+    blog_os::scheduler::resume(consumer);
+    blog_os::process_table::set_next_process(producer);
+    blog_os::process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn producer() {
+    let handle = unsafe { HANDLE.unwrap() };
+    let vm_pool = &mut blog_os::process_table::get_curr_process_table_mut().vm_pool;
+    let addr = vm_pool.map_shared(handle, Protection::READ | Protection::WRITE).unwrap();
+    unsafe {
+        *(addr.as_u64() as *mut u64) = 1234;
+        *((addr + PAGE_SIZE).as_u64() as *mut u64) = 5678;
+    }
+
+    while unsafe { !CONSUMED } {
+        blog_os::scheduler::_yield();
+    }
+    if unsafe { *((addr + 8u64).as_u64() as *mut u64) } != 99 {
+        panic!("no reply from the consumer");
+    }
+
+    // the frames go once the last mapping and the handle are gone
+    let frame = shared_memory::frame(handle, 0).unwrap();
+    vm_pool.release(addr);
+    if blog_os::memory::frame_ref_count(frame) != 1 {
+        panic!("{} references left while the handle is open", blog_os::memory::frame_ref_count(frame));
+    }
+    shared_memory::close(handle);
+    if blog_os::memory::frame_ref_count(frame) != 0 || shared_memory::page_count(handle).is_some() {
+        panic!("shared object not torn down");
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn consumer() {
+    let handle = unsafe { HANDLE.unwrap() };
+    let vm_pool = &mut blog_os::process_table::get_curr_process_table_mut().vm_pool;
+    let addr = vm_pool.map_shared(handle, Protection::READ | Protection::WRITE).unwrap();
+    unsafe {
+        if *(addr.as_u64() as *mut u64) != 1234 || *((addr + PAGE_SIZE).as_u64() as *mut u64) != 5678 {
+            panic!("producer's data not visible");
+        }
+        *((addr + 8u64).as_u64() as *mut u64) = 99;
+    }
+    vm_pool.release(addr);
+    unsafe { CONSUMED = true; }
+
+    loop {
+        blog_os::scheduler::_yield();
+    }
+}
@@ -0,0 +1,81 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::MyProcess;
+use blog_os::slab::SlabCache;
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+static mut TEST_CACHE : SlabCache = SlabCache::new(100);
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    // processes share slabs
+    for _i in 0..3 {
+        MyProcess::new(process_function as blog_os::machine::CFunc);
+    }
+    let process_cache = blog_os::process_table::get_process_cache();
+    if process_cache.objects_in_use() != 3 || process_cache.slab_count() != 1 {
+        panic!("{} processes in {} slabs", process_cache.objects_in_use(), process_cache.slab_count());
+    }
+
+    let cache = unsafe { &mut TEST_CACHE };
+    let per_slab = cache.objects_per_slab();
+    let mut objects = [VirtAddr::new(0); 64];
+    let count = per_slab as usize + 1;
+
+    for i in 0..count {
+        objects[i] = cache.allocate().unwrap();
+        unsafe { *(objects[i].as_u64() as *mut u64) = i as u64; }
+    }
+    if cache.slab_count() != 2 || cache.objects_in_use() != count as u64 {
+        panic!("{} objects in {} slabs", cache.objects_in_use(), cache.slab_count());
+    }
+
+    for i in 0..count {
+        if unsafe { *(objects[i].as_u64() as *mut u64) } != i as u64 {
+            panic!("object {} was overwritten", i);
+        }
+    }
+
+    // empty slabs are handed back
+    for i in 0..count {
+        cache.free(objects[i]);
+    }
+    if cache.slab_count() != 0 || cache.objects_in_use() != 0 {
+        panic!("{} objects in {} slabs", cache.objects_in_use(), cache.slab_count());
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn process_function() {
+    serial_println!("process function");
+}
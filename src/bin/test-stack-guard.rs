@@ -0,0 +1,77 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::{self, ProcessBuilder, KILLED_EXIT_CODE};
+use blog_os::machine::PAGE_SIZE;
+
+entry_point!(kernel_main);
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let growing = ProcessBuilder::new(growing as blog_os::machine::CFunc)
+        .stack_size(PAGE_SIZE)
+        .stack_limit(16 * PAGE_SIZE)
+        .spawn().unwrap();
+    process_table::set_next_process(growing);
+    process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+// every call takes a bit over 512 bytes of stack
+fn recurse(depth : u64) -> u64 {
+    let mut buffer = [0u8; 512];
+    unsafe { core::ptr::write_volatile(&mut buffer[0], depth as u8); }
+    if depth == 0 {
+        return 0;
+    }
+    recurse(depth - 1) + unsafe { core::ptr::read_volatile(&buffer[0]) } as u64
+}
+
+extern "C" fn growing() {
+    // about 32KB, far more than the one page it starts with
+    recurse(64);
+
+    // running out of stack only takes down the process that did
+    let fixed = ProcessBuilder::new(overflowing as blog_os::machine::CFunc)
+        .stack_size(2 * PAGE_SIZE)
+        .stack_limit(2 * PAGE_SIZE)
+        .spawn().unwrap();
+    let pid = fixed.process_id;
+    blog_os::scheduler::resume(fixed);
+    if process_table::wait(pid) != Some(KILLED_EXIT_CODE) {
+        panic!("overflowing process was not killed");
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn overflowing() {
+    recurse(1 << 20);
+}
@@ -0,0 +1,192 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::{self, MyProcess, ProcessBuilder};
+use blog_os::shared_memory;
+use blog_os::syscall::{self, SyscallError};
+use blog_os::vm_pool::Protection;
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+const CODE_ADDR : u64 = 0x4000_0000;
+const SHARED_ADDR : u64 = 0x4010_0000;
+
+// Stores the result of each call in the shared page rdi points to:
+// getpid, write of the "hello" at CODE_ADDR + 0x80, write(0x1000, 5), call 99, allocate(0x1000),
+// release of that region after writing to it, then yield and exit(5).
+const CODE : [u8; 122] = [
+    0x48, 0x89, 0xfb, // mov rbx, rdi
+    0xb8, 0x05, 0x00, 0x00, 0x00, // mov eax, SYS_GETPID
+    0xcd, 0x80,
+    0x48, 0x89, 0x03, // mov [rbx], rax
+    0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, SYS_WRITE
+    0xbf, 0x80, 0x00, 0x00, 0x40, // mov edi, CODE_ADDR + 0x80
+    0xbe, 0x05, 0x00, 0x00, 0x00, // mov esi, 5
+    0xcd, 0x80,
+    0x48, 0x89, 0x43, 0x08, // mov [rbx+8], rax
+    0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, SYS_WRITE
+    0xbf, 0x00, 0x10, 0x00, 0x00, // mov edi, 0x1000
+    0xbe, 0x05, 0x00, 0x00, 0x00, // mov esi, 5
+    0xcd, 0x80,
+    0x48, 0x89, 0x43, 0x10, // mov [rbx+16], rax
+    0xb8, 0x63, 0x00, 0x00, 0x00, // mov eax, 99
+    0xcd, 0x80,
+    0x48, 0x89, 0x43, 0x18, // mov [rbx+24], rax
+    0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, SYS_ALLOCATE
+    0xbf, 0x00, 0x10, 0x00, 0x00, // mov edi, 0x1000
+    0xcd, 0x80,
+    0x48, 0x89, 0x43, 0x20, // mov [rbx+32], rax
+    0x48, 0xc7, 0x00, 0x07, 0x00, 0x00, 0x00, // mov qword [rax], 7
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0xb8, 0x04, 0x00, 0x00, 0x00, // mov eax, SYS_RELEASE
+    0xcd, 0x80,
+    0x48, 0x89, 0x43, 0x28, // mov [rbx+40], rax
+    0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, SYS_YIELD
+    0xcd, 0x80,
+    0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, SYS_EXIT
+    0xbf, 0x05, 0x00, 0x00, 0x00, // mov edi, 5
+    0xcd, 0x80
+];
+
+fn put(image : &mut [u8], at : usize, value : u64, bytes : usize) {
+    for i in 0..bytes {
+        image[at + i] = (value >> (8 * i)) as u8;
+    }
+}
+
+// an executable with nothing but a read/execute code segment
+fn build_image(image : &mut [u8]) {
+    image[0..4].copy_from_slice(&[0x7f, b'E', b'L', b'F']);
+    image[4] = 2; // 64 bit
+    image[5] = 1; // little endian
+    image[6] = 1; // version
+    put(image, 16, 2, 2); // executable
+    put(image, 18, 0x3e, 2); // x86_64
+    put(image, 20, 1, 4);
+    put(image, 24, CODE_ADDR, 8); // entry
+    put(image, 32, 64, 8); // program headers right after this header
+    put(image, 52, 64, 2);
+    put(image, 54, 56, 2);
+    put(image, 56, 1, 2);
+
+    put(image, 64, 1, 4); // PT_LOAD
+    put(image, 64 + 4, 5, 4);
+    put(image, 64 + 8, 0x100, 8);
+    put(image, 64 + 16, CODE_ADDR, 8);
+    put(image, 64 + 24, CODE_ADDR, 8);
+    put(image, 64 + 32, 0x100, 8);
+    put(image, 64 + 40, 0x100, 8);
+    put(image, 64 + 48, 0x1000, 8);
+
+    image[0x100..0x100 + CODE.len()].copy_from_slice(&CODE);
+    image[0x180..0x185].copy_from_slice(b"hello");
+}
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let parent = MyProcess::new(parent as blog_os::machine::CFunc);
+    process_table::set_next_process(parent);
+    process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+static mut RAN_WHILE_SLEEPING : bool = false;
+
+extern "C" fn run_while_sleeping() {
+    unsafe { RAN_WHILE_SLEEPING = true; }
+}
+
+extern "C" fn parent() {
+    // the calls work from ring 0 too
+    if syscall::getpid() != process_table::get_curr_process_table().process_id {
+        panic!("wrong pid");
+    }
+    if syscall::write("kernel text") != Err(SyscallError::BadAddress) {
+        panic!("wrote memory outside the VMPool");
+    }
+    let region = syscall::allocate(0x2000).unwrap();
+    unsafe { *(region.as_u64() as *mut u64) = 1; }
+    if syscall::release(region + 0x1000u64) != Err(SyscallError::InvalidArgument)
+        || syscall::release(region) != Ok(()) {
+        panic!("release of {:?}", region);
+    }
+    if SyscallError::from_result(syscall::syscall(99, 0, 0, 0)) != Some(SyscallError::NoSuchCall) {
+        panic!("call 99 exists");
+    }
+    // sleeping hands the cpu to the others
+    let other = ProcessBuilder::new(run_while_sleeping as blog_os::machine::CFunc).name("other").spawn().unwrap();
+    blog_os::scheduler::resume(other);
+    let before = blog_os::interrupts::ticks();
+    syscall::sleep(2);
+    if blog_os::interrupts::ticks() < before + 2 {
+        panic!("woke up too early");
+    }
+    if !unsafe { RAN_WHILE_SLEEPING } {
+        panic!("nothing ran while sleeping");
+    }
+
+    let handle = shared_memory::create(blog_os::machine::PAGE_SIZE).unwrap();
+    let shared = process_table::get_curr_process_table_mut().vm_pool
+        .map_shared(handle, Protection::READ | Protection::WRITE).unwrap().as_u64() as *const u64;
+    let mut image = [0u8; 0x200];
+    build_image(&mut image);
+    let program = ProcessBuilder::program()
+        .args(&[SHARED_ADDR])
+        .name("program")
+        .spawn_elf(&image)
+        .unwrap();
+    program.vm_pool.map_shared_at(VirtAddr::new(SHARED_ADDR), handle, Protection::READ | Protection::WRITE).unwrap();
+    let pid = program.process_id;
+    blog_os::scheduler::resume(program);
+    if process_table::wait(pid) != Some(5) {
+        panic!("program did not exit with 5");
+    }
+
+    let results = unsafe { core::slice::from_raw_parts(shared, 6) };
+    if results[0] != u64::from(pid) {
+        panic!("program got pid {}", results[0]);
+    }
+    if results[1] != 5 {
+        panic!("write returned {:#x}", results[1]);
+    }
+    if results[2] != SyscallError::BadAddress.code() {
+        panic!("write of kernel memory returned {:#x}", results[2]);
+    }
+    if results[3] != SyscallError::NoSuchCall.code() {
+        panic!("call 99 returned {:#x}", results[3]);
+    }
+    if results[4] < blog_os::machine::HEAP_START || results[5] != 0 {
+        panic!("allocate returned {:#x}, release {:#x}", results[4], results[5]);
+    }
+
+    shared_memory::close(handle);
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
@@ -0,0 +1,151 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::{self, MyProcess};
+use x86_64::{VirtAddr, registers::control::Cr3};
+
+entry_point!(kernel_main);
+
+const THREADS : usize = 3;
+const ITERATIONS : u64 = 10;
+
+// lives in the VMPool of the main process, the threads only see it if they share it
+#[repr(C)]
+struct Shared {
+    counter : u64,
+    cr3 : [u64; THREADS],
+    stack : [u64; THREADS] // start of the stack region
+}
+
+static mut SHARED : *mut Shared = 0x0 as *mut Shared;
+static mut SPINNER_ID : u16 = 0;
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let process = MyProcess::new(main_thread as blog_os::machine::CFunc);
+    process_table::set_next_process(process);
+    process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn worker(index : u64) {
+    let shared = unsafe { &mut *SHARED };
+    let local = 0u64;
+    let (stack, _) = process_table::get_curr_process_table().vm_pool
+        .get_region(VirtAddr::new(&local as *const u64 as u64)).unwrap();
+    shared.stack[index as usize] = stack.as_u64();
+    shared.cr3[index as usize] = Cr3::read().0.start_address().as_u64();
+    for _ in 0..ITERATIONS {
+        shared.counter += 1;
+        blog_os::scheduler::_yield();
+    }
+    // returning ends the thread with 0
+    if index != 0 {
+        process_table::thread_exit(index * 10);
+    }
+}
+
+extern "C" fn spinner(_arg : u64) {
+    loop {
+        blog_os::scheduler::_yield();
+    }
+}
+
+// starts a thread that never ends and returns, which ends the thread as well
+extern "C" fn leader() {
+    unsafe { SPINNER_ID = process_table::thread_create(spinner, 0).unwrap(); }
+    blog_os::scheduler::_yield();
+}
+
+extern "C" fn main_thread() {
+    let process = process_table::get_curr_process_table_mut();
+    let region = process.vm_pool.allocate(core::mem::size_of::<Shared>()).unwrap();
+    unsafe {
+        SHARED = region.as_u64() as *mut Shared;
+        core::ptr::write_bytes(SHARED, 0, 1);
+    }
+    let regions = process.vm_pool.region_count();
+
+    let mut tids = [0u16; THREADS];
+    for i in 0..THREADS {
+        tids[i] = process_table::thread_create(worker, i as u64).unwrap();
+        let thread = process_table::get_process(tids[i]).unwrap();
+        if thread.thread_group() != process.process_id || thread.parent_id() != process.process_id {
+            panic!("thread {} belongs to {}", tids[i], thread.thread_group());
+        }
+    }
+    if process.threads().count() != THREADS + 1 {
+        panic!("{} threads", process.threads().count());
+    }
+
+    for i in 0..THREADS {
+        let code = if i == 0 { 0 } else { i as u64 * 10 };
+        if process_table::thread_join(tids[i]) != Some(code) {
+            panic!("thread {} did not exit with {}", tids[i], code);
+        }
+    }
+
+    let shared = unsafe { &*SHARED };
+    if shared.counter != THREADS as u64 * ITERATIONS {
+        panic!("counted to {}", shared.counter);
+    }
+    // one page table, a stack each
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    let (own_stack, _) = process.vm_pool.get_region(VirtAddr::new(&cr3 as *const u64 as u64)).unwrap();
+    for i in 0..THREADS {
+        if shared.cr3[i] != cr3 {
+            panic!("thread {} ran on page table {:#x}", i, shared.cr3[i]);
+        }
+        if shared.stack[i] == own_stack.as_u64() || shared.stack[..i].contains(&shared.stack[i]) {
+            panic!("thread {} ran on stack {:#x}", i, shared.stack[i]);
+        }
+    }
+    // their stacks went with them
+    if process.vm_pool.region_count() != regions {
+        panic!("{} regions left, {} before", process.vm_pool.region_count(), regions);
+    }
+
+    if process_table::thread_join(process.process_id).is_some() || process_table::thread_join(0xffff).is_some() {
+        panic!("joined something that isn't another thread");
+    }
+
+    // a process ending takes its threads with it
+    let child = MyProcess::new(leader as blog_os::machine::CFunc);
+    let pid = child.process_id;
+    blog_os::scheduler::resume(child);
+    if process_table::wait(pid) != Some(0) {
+        panic!("process did not exit");
+    }
+    if process_table::get_process(unsafe { SPINNER_ID }).is_some() {
+        panic!("thread outlived its process");
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
@@ -0,0 +1,98 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::{self, MyProcess};
+use blog_os::syscall::{self, SyscallError};
+use blog_os::user_memory;
+use blog_os::vm_pool::Protection;
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+static KERNEL_DATA : [u8; 8] = *b"kernel!!";
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let process = MyProcess::new(copy_test as blog_os::machine::CFunc);
+    process_table::set_next_process(process);
+    process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn copy_test() {
+    let vm_pool = &mut process_table::get_curr_process_table_mut().vm_pool;
+    let pool_end = blog_os::machine::HEAP_START + blog_os::machine::HEAP_SIZE;
+
+    // a round trip through memory of the process
+    let region = vm_pool.allocate(blog_os::machine::PAGE_SIZE as usize).unwrap().as_u64();
+    user_memory::copy_to_user(region, b"hello").unwrap();
+    let mut buffer = [0u8; 5];
+    user_memory::copy_from_user(&mut buffer, region).unwrap();
+    if &buffer != b"hello" {
+        panic!("copied back {:?}", buffer);
+    }
+
+    // what the VMPool doesn't allow is refused up front
+    let read_only = vm_pool.allocate_with(blog_os::machine::PAGE_SIZE as usize, Protection::READ).unwrap();
+    if user_memory::copy_to_user(read_only.as_u64(), b"hello") != Err(SyscallError::BadAddress) {
+        panic!("copied to a read-only region");
+    }
+    if user_memory::copy_from_user(&mut buffer, KERNEL_DATA.as_ptr() as u64) != Err(SyscallError::BadAddress) {
+        panic!("copied from kernel memory");
+    }
+    if user_memory::copy_from_user(&mut buffer, pool_end - 2) != Err(SyscallError::BadAddress) {
+        panic!("copied from past the VMPool");
+    }
+
+    // a fault in the middle of a copy ends it instead of the kernel
+    let last_page = VirtAddr::new(pool_end - blog_os::machine::PAGE_SIZE);
+    let last = vm_pool.allocate_at(last_page, blog_os::machine::PAGE_SIZE as usize,
+                                   Protection::READ | Protection::WRITE).unwrap();
+    if last != last_page {
+        panic!("last page allocated at {:?}", last);
+    }
+    user_memory::copy_to_user(pool_end - 8, b"12345678").unwrap();
+    let mut straddling = [0u8; 16];
+    let left = unsafe { user_memory::copy_unchecked(straddling.as_mut_ptr(), (pool_end - 8) as *const u8, 16) };
+    if left != 8 || &straddling[..8] != b"12345678" {
+        panic!("{} bytes left, copied {:?}", left, straddling);
+    }
+    let left = unsafe { user_memory::copy_unchecked(buffer.as_mut_ptr(), 0x0000_8000_0000_0000 as *const u8, 5) };
+    if left != 5 {
+        panic!("copied from a non canonical address");
+    }
+
+    // and so do system calls
+    if syscall::syscall(syscall::SYS_WRITE, pool_end - 2, 4, 0) != SyscallError::BadAddress.code() {
+        panic!("wrote from past the VMPool");
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
@@ -0,0 +1,188 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::{self, MyProcess, ProcessBuilder};
+use blog_os::shared_memory;
+use blog_os::vm_pool::Protection;
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+const CODE_ADDR : u64 = 0x4000_0000;
+const SHARED_ADDR : u64 = 0x4010_0000;
+
+// mov qword [rdi], 42; mov rax, cs; mov [rdi+8], rax; mov qword [rsi], 0xbad; ret
+const SCRIBBLER : [u8; 22] = [
+    0x48, 0xc7, 0x07, 0x2a, 0x00, 0x00, 0x00,
+    0x48, 0x8c, 0xc8,
+    0x48, 0x89, 0x47, 0x08,
+    0x48, 0xc7, 0x06, 0xad, 0x0b, 0x00, 0x00,
+    0xc3
+];
+
+// cli; ret
+const PRIVILEGED : [u8; 2] = [0xfa, 0xc3];
+
+// or rax, rbx; or rax, rbp; or rax, r10 ... r15; mov [rdi+16], rax; ret
+const REGISTERS : [u8; 29] = [
+    0x48, 0x09, 0xd8,
+    0x48, 0x09, 0xe8,
+    0x4c, 0x09, 0xd0,
+    0x4c, 0x09, 0xd8,
+    0x4c, 0x09, 0xe0,
+    0x4c, 0x09, 0xe8,
+    0x4c, 0x09, 0xf0,
+    0x4c, 0x09, 0xf8,
+    0x48, 0x89, 0x47, 0x10,
+    0xc3
+];
+
+// ud2
+const UNDEFINED : [u8; 2] = [0x0f, 0x0b];
+
+// xor ecx, ecx; div rcx
+const DIVIDING : [u8; 5] = [0x31, 0xc9, 0x48, 0xf7, 0xf1];
+
+static mut KERNEL_DATA : u64 = 7;
+
+fn put(image : &mut [u8], at : usize, value : u64, bytes : usize) {
+    for i in 0..bytes {
+        image[at + i] = (value >> (8 * i)) as u8;
+    }
+}
+
+// an executable with nothing but a read/execute code segment
+fn build_image(image : &mut [u8], code : &[u8]) {
+    image[0..4].copy_from_slice(&[0x7f, b'E', b'L', b'F']);
+    image[4] = 2; // 64 bit
+    image[5] = 1; // little endian
+    image[6] = 1; // version
+    put(image, 16, 2, 2); // executable
+    put(image, 18, 0x3e, 2); // x86_64
+    put(image, 20, 1, 4);
+    put(image, 24, CODE_ADDR, 8); // entry
+    put(image, 32, 64, 8); // program headers right after this header
+    put(image, 52, 64, 2);
+    put(image, 54, 56, 2);
+    put(image, 56, 1, 2);
+
+    put(image, 64, 1, 4); // PT_LOAD
+    put(image, 64 + 4, 5, 4);
+    put(image, 64 + 8, 0x100, 8);
+    put(image, 64 + 16, CODE_ADDR, 8);
+    put(image, 64 + 24, CODE_ADDR, 8);
+    put(image, 64 + 32, code.len() as u64, 8);
+    put(image, 64 + 40, code.len() as u64, 8);
+    put(image, 64 + 48, 0x1000, 8);
+
+    image[0x100..0x100 + code.len()].copy_from_slice(code);
+}
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let parent = MyProcess::new(parent as blog_os::machine::CFunc);
+    process_table::set_next_process(parent);
+    process_table::process_switch_to();
+
+    panic!();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn parent() {
+    let handle = shared_memory::create(blog_os::machine::PAGE_SIZE).unwrap();
+    let shared = process_table::get_curr_process_table_mut().vm_pool
+        .map_shared(handle, Protection::READ | Protection::WRITE).unwrap().as_u64() as *const u64;
+
+    // writes to its own memory go through, the write to the kernel's ends the process
+    let mut image = [0u8; 0x200];
+    build_image(&mut image, &SCRIBBLER);
+    let scribbler = ProcessBuilder::program()
+        .args(&[SHARED_ADDR, unsafe { &mut KERNEL_DATA as *mut u64 as u64 }])
+        .name("scribbler")
+        .spawn_elf(&image)
+        .unwrap();
+    scribbler.vm_pool.map_shared_at(VirtAddr::new(SHARED_ADDR), handle, Protection::READ | Protection::WRITE).unwrap();
+    if !scribbler.is_user() {
+        panic!("program does not run in ring 3");
+    }
+    let pid = scribbler.process_id;
+    blog_os::scheduler::resume(scribbler);
+    if process_table::wait(pid) != Some(process_table::KILLED_EXIT_CODE) {
+        panic!("scribbler was not killed");
+    }
+    if unsafe { *shared } != 42 {
+        panic!("scribbler wrote {} to its own memory", unsafe { *shared });
+    }
+    if unsafe { *shared.offset(1) } & 3 != 3 {
+        panic!("scribbler ran with cs {:#x}", unsafe { *shared.offset(1) });
+    }
+    if unsafe { KERNEL_DATA } != 7 {
+        panic!("kernel memory was overwritten");
+    }
+
+    // so does an instruction only the kernel may use
+    let mut image = [0u8; 0x200];
+    build_image(&mut image, &PRIVILEGED);
+    let privileged = ProcessBuilder::program().name("privileged").spawn_elf(&image).unwrap();
+    let pid = privileged.process_id;
+    blog_os::scheduler::resume(privileged);
+    if process_table::wait(pid) != Some(process_table::KILLED_EXIT_CODE) {
+        panic!("privileged instruction did not kill the process");
+    }
+
+    // and any other fault of its own
+    for code in [&UNDEFINED[..], &DIVIDING[..]].iter() {
+        let mut image = [0u8; 0x200];
+        build_image(&mut image, code);
+        let faulting = ProcessBuilder::program().name("faulting").spawn_elf(&image).unwrap();
+        let pid = faulting.process_id;
+        blog_os::scheduler::resume(faulting);
+        if process_table::wait(pid) != Some(process_table::KILLED_EXIT_CODE) {
+            panic!("fault in {:x?} did not kill the process", code);
+        }
+    }
+
+    // nothing the kernel had in its registers makes it to ring 3
+    unsafe { *(shared as *mut u64).offset(2) = 1; }
+    let mut image = [0u8; 0x200];
+    build_image(&mut image, &REGISTERS);
+    let registers = ProcessBuilder::program()
+        .args(&[SHARED_ADDR])
+        .name("registers")
+        .spawn_elf(&image)
+        .unwrap();
+    registers.vm_pool.map_shared_at(VirtAddr::new(SHARED_ADDR), handle, Protection::READ | Protection::WRITE).unwrap();
+    let pid = registers.process_id;
+    blog_os::scheduler::resume(registers);
+    if process_table::wait(pid) != Some(0) || unsafe { *shared.offset(2) } != 0 {
+        panic!("program started with {:#x} in its registers", unsafe { *shared.offset(2) });
+    }
+
+    shared_memory::close(handle);
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::MyProcess;
+use blog_os::vm_pool::VMError;
+
+entry_point!(kernel_main);
+
+const REGIONS : u64 = 1000;
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let mut page_table : &'static mut MyProcess = MyProcess::new(process_function as blog_os::machine::CFunc);
+    page_table = page_table.load_page_table();
+    let x = &mut page_table.vm_pool;
+    let regions_before = x.region_count() as u64;
+
+    // far more regions than used to fit in one frame
+    let first = x.allocate(blog_os::machine::PAGE_SIZE as usize).unwrap();
+    for i in 1..REGIONS {
+        let addr = x.allocate(blog_os::machine::PAGE_SIZE as usize).unwrap();
+        if addr != first + i * blog_os::machine::PAGE_SIZE {
+            panic!("region {} at {:?}", i, addr);
+        }
+    }
+    if x.region_count() as u64 != regions_before + REGIONS {
+        panic!("{} regions recorded", x.region_count());
+    }
+    for i in 0..REGIONS {
+        if !x.is_legitimate(first + i * blog_os::machine::PAGE_SIZE) {
+            panic!("region {} lost", i);
+        }
+    }
+
+    // requests that can't be satisfied are reported
+    if x.allocate(0) != Err(VMError::InvalidSize) || x.allocate(usize::max_value()) != Err(VMError::InvalidSize) {
+        panic!();
+    }
+    if x.allocate(blog_os::machine::HEAP_SIZE as usize) != Err(VMError::OutOfSpace) {
+        panic!();
+    }
+
+    // the table grows until the kernel heap has no room left for it, which is an error as well
+    let mut count = REGIONS;
+    let error = loop {
+        match x.allocate(blog_os::machine::PAGE_SIZE as usize) {
+            Ok(_) => count += 1,
+            Err(error) => break error
+        }
+    };
+    if error != VMError::OutOfSpace || count >= blog_os::machine::HEAP_SIZE / blog_os::machine::PAGE_SIZE {
+        panic!("{:?} after {} regions", error, count);
+    }
+    for i in 0..count {
+        x.release(first + i * blog_os::machine::PAGE_SIZE);
+    }
+    if x.region_count() as u64 != regions_before || x.allocate(blog_os::machine::PAGE_SIZE as usize) != Ok(first) {
+        panic!("{} regions after releasing them", x.region_count());
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn process_function() {
+    serial_println!("process function");
+}
@@ -0,0 +1,74 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::MyProcess;
+use blog_os::vm_pool::Protection;
+
+entry_point!(kernel_main);
+
+// only the fault of the last step may end the test
+static mut READING_NO_ACCESS : bool = false;
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let mut page_table : &'static mut MyProcess = MyProcess::new(process_function as blog_os::machine::CFunc);
+    page_table = page_table.load_page_table();
+    let x = &mut page_table.vm_pool;
+
+    let addr = x.allocate_with(blog_os::machine::PAGE_SIZE as usize, Protection::READ | Protection::WRITE).unwrap();
+    let test_mem = addr.as_u64() as *mut u64;
+    unsafe {
+        *test_mem = 42;
+    }
+
+    // the page is mapped already, taking READ away must not leave it readable
+    if !x.protect(addr, Protection::NONE) {
+        panic!();
+    }
+    // but it keeps its contents for when it is readable again
+    x.protect(addr, Protection::READ);
+    if unsafe { *test_mem } != 42 {
+        panic!();
+    }
+
+    x.protect(addr, Protection::NONE);
+    unsafe {
+        READING_NO_ACCESS = true;
+        core::ptr::read_volatile(test_mem); // this should panic
+    }
+
+    serial_println!("failed");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if unsafe { READING_NO_ACCESS } {
+        serial_println!("ok");
+    } else {
+        serial_println!("failed");
+        serial_println!("{}", info);
+    }
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn process_function() {
+    serial_println!("process function");
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::MyProcess;
+use blog_os::vm_pool::VMError;
+use blog_os::machine::PAGE_SIZE;
+
+entry_point!(kernel_main);
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let mut page_table : &'static mut MyProcess = MyProcess::new(process_function as blog_os::machine::CFunc);
+    page_table = page_table.load_page_table();
+    let x = &mut page_table.vm_pool;
+    let regions_before = x.region_count();
+
+    // 8 pages, every other one touched
+    let addr = x.allocate(8 * PAGE_SIZE as usize).unwrap();
+    for i in 0..4 {
+        unsafe { *((addr + 2 * i * PAGE_SIZE).as_u64() as *mut u64) = i; }
+    }
+    let free_before = blog_os::memory::stats().user.free_frames;
+
+    if x.release_range(addr + 1u64, PAGE_SIZE) != Err(VMError::Unaligned) {
+        panic!();
+    }
+
+    // a hole in the middle splits the region
+    x.release_range(addr + 2 * PAGE_SIZE, 3 * PAGE_SIZE).unwrap();
+    if x.region_count() != regions_before + 2 {
+        panic!("{} regions", x.region_count());
+    }
+    if x.is_legitimate(addr + 3 * PAGE_SIZE) || !x.is_legitimate(addr + PAGE_SIZE) || !x.is_legitimate(addr + 5 * PAGE_SIZE) {
+        panic!("wrong pages released");
+    }
+    // pages 2 and 4 were mapped, page 3 never was
+    if blog_os::memory::stats().user.free_frames != free_before + 2 {
+        panic!("expected 2 frames back");
+    }
+    unsafe {
+        if *((addr + 6 * PAGE_SIZE).as_u64() as *mut u64) != 3 {
+            panic!("the tail lost its contents");
+        }
+    }
+
+    // trimming both ends, and a range that spans the hole
+    x.release_range(addr, PAGE_SIZE).unwrap();
+    x.release_range(addr + 7 * PAGE_SIZE, PAGE_SIZE).unwrap();
+    if x.is_legitimate(addr) || !x.is_legitimate(addr + PAGE_SIZE) || x.is_legitimate(addr + 7 * PAGE_SIZE) {
+        panic!("ends not trimmed");
+    }
+    x.release_range(addr, 8 * PAGE_SIZE).unwrap();
+    if x.region_count() != regions_before {
+        panic!("{} regions left", x.region_count());
+    }
+    if blog_os::memory::stats().user.free_frames != free_before + 4 {
+        panic!("frames leaked");
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn process_function() {
+    serial_println!("process function");
+}
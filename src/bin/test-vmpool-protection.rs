@@ -0,0 +1,76 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::MyProcess;
+use blog_os::vm_pool::Protection;
+
+entry_point!(kernel_main);
+
+// only the fault of the last step may end the test
+static mut WRITING_READ_ONLY : bool = false;
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let mut page_table : &'static mut MyProcess = MyProcess::new(process_function as blog_os::machine::CFunc);
+    page_table = page_table.load_page_table();
+    let x = &mut page_table.vm_pool;
+
+    // a read-only region can be read
+    let addr = x.allocate_with(blog_os::machine::PAGE_SIZE as usize, Protection::READ).unwrap();
+    let test_mem = addr.as_u64() as *mut u64;
+    if unsafe { *test_mem } != 0 {
+        panic!();
+    }
+
+    // the page is already mapped, protect has to update it
+    if !x.protect(addr, Protection::READ | Protection::WRITE) {
+        panic!();
+    }
+    unsafe {
+        *test_mem = 42;
+        if *test_mem != 42 {
+            panic!();
+        }
+    }
+
+    x.protect(addr, Protection::READ);
+    unsafe {
+        WRITING_READ_ONLY = true;
+        *test_mem = 0; // this should panic
+    }
+
+    serial_println!("failed");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if unsafe { WRITING_READ_ONLY } {
+        serial_println!("ok");
+    } else {
+        serial_println!("failed");
+        serial_println!("{}", info);
+    }
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn process_function() {
+    serial_println!("process function");
+}
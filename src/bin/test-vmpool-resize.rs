@@ -0,0 +1,102 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::MyProcess;
+use blog_os::vm_pool::VMError;
+use blog_os::machine::PAGE_SIZE;
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+fn free_user_frames() -> u64 {
+    blog_os::memory::stats().user.free_frames
+}
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let mut page_table : &'static mut MyProcess = MyProcess::new(process_function as blog_os::machine::CFunc);
+    page_table = page_table.load_page_table();
+    let x = &mut page_table.vm_pool;
+
+    // a region only grows into free address space
+    let a = x.allocate(2 * PAGE_SIZE as usize).unwrap();
+    let b = x.allocate(PAGE_SIZE as usize).unwrap();
+    if x.resize(a, 3 * PAGE_SIZE as usize) != Err(VMError::OutOfSpace) {
+        panic!("grew over the next region");
+    }
+    x.release(b);
+    x.resize(a, 3 * PAGE_SIZE as usize).unwrap();
+    unsafe { *((a + 2 * PAGE_SIZE).as_u64() as *mut u64) = 42; }
+
+    // shrinking gives the tail back
+    let free_before = free_user_frames();
+    x.resize(a, PAGE_SIZE as usize).unwrap();
+    if x.is_legitimate(a + 2 * PAGE_SIZE) || free_user_frames() != free_before + 1 {
+        panic!("tail not released");
+    }
+    if x.resize(b, PAGE_SIZE as usize) != Err(VMError::NoRegion) {
+        panic!();
+    }
+    // sizes that overflow or reach past the pool are refused
+    if x.resize(a, usize::max_value()) != Err(VMError::InvalidSize)
+        || x.resize(a, usize::max_value() - 2 * PAGE_SIZE as usize) != Err(VMError::OutOfSpace) {
+        panic!("resized past the pool");
+    }
+
+    // the program break
+    let heap = x.sbrk(0).unwrap();
+    if x.sbrk(100).unwrap() != heap || x.program_break().unwrap() != heap + 100u64 {
+        panic!("break not moved");
+    }
+    let grown = x.sbrk(3 * PAGE_SIZE as i64).unwrap();
+    for i in 0..(3 * PAGE_SIZE / 8) {
+        unsafe { *((grown + i * 8).as_u64() as *mut u64) = i; }
+    }
+    let free_before = free_user_frames();
+    x.sbrk(-(3 * PAGE_SIZE as i64)).unwrap();
+    if x.program_break().unwrap() != heap + 100u64 || free_user_frames() != free_before + 3 {
+        panic!("heap not shrunk");
+    }
+    if x.brk(heap - 1u64) != Err(VMError::InvalidSize) {
+        panic!();
+    }
+    // a break past the pool is refused, not turned into an address
+    if x.sbrk(i64::max_value()) != Err(VMError::InvalidSize) || x.sbrk(0x0000_8000_0000_0000) != Err(VMError::InvalidSize) {
+        panic!("break moved past the pool");
+    }
+    if x.brk(VirtAddr::new(0xffff_ffff_ffff_f000)) != Err(VMError::OutOfSpace) {
+        panic!("break moved past the pool");
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn process_function() {
+    serial_println!("process function");
+}
@@ -0,0 +1,86 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+use blog_os::{exit_qemu, serial_println};
+use bootloader::{bootinfo::BootInfo, entry_point};
+use core::panic::PanicInfo;
+use blog_os::process_table::MyProcess;
+use blog_os::vm_pool::Protection;
+use x86_64::VirtAddr;
+
+entry_point!(kernel_main);
+
+const MB : usize = 0o4_000_000;
+
+#[cfg(not(test))]
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    blog_os::gdt::init();
+    blog_os::interrupts::init_idt();
+    blog_os::interrupts::init_pics();
+    blog_os::interrupts::enable_interrupts();
+
+    blog_os::memory::init_frame_allocator(&boot_info.memory_map);
+
+    let mut page_table : &'static mut MyProcess = MyProcess::new(process_function as blog_os::machine::CFunc);
+    page_table = page_table.load_page_table();
+    let x = &mut page_table.vm_pool;
+
+    // 4GB worth of buffers go through the 1GB pool
+    for i in 0..1024 {
+        let addr = x.allocate(4 * MB).unwrap_or_else(|_| panic!("pool exhausted after {} buffers", i));
+        unsafe { *(addr.as_u64() as *mut u64) = i; }
+        x.release(addr);
+    }
+
+    // a hole is filled by the first allocation that fits
+    let a = x.allocate(MB).unwrap();
+    let b = x.allocate(2 * MB).unwrap();
+    let c = x.allocate(MB).unwrap();
+    x.release(b);
+    if x.allocate(3 * MB).unwrap() <= c {
+        panic!("a region larger than the hole went into it");
+    }
+    let b1 = x.allocate(MB).unwrap();
+    let b2 = x.allocate(MB).unwrap();
+    if b1 != b || b2 != b + MB as u64 {
+        panic!("hole not reused");
+    }
+
+    // neighbouring holes merge
+    x.release(a);
+    x.release(b1);
+    if x.allocate(2 * MB).unwrap() != a {
+        panic!("holes not merged");
+    }
+
+    // a free hint is taken as is, a taken one moves to the next hole
+    let hint = VirtAddr::new(blog_os::machine::HEAP_START + 512 * MB as u64);
+    if x.allocate_at(hint, MB, Protection::READ).unwrap() != hint {
+        panic!("free hint not used");
+    }
+    if x.allocate_at(hint, MB, Protection::READ).unwrap() != hint + MB as u64 {
+        panic!("taken hint not moved past");
+    }
+
+    serial_println!("ok");
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    blog_os::hlt_loop();
+}
+
+extern "C" fn process_function() {
+    serial_println!("process function");
+}
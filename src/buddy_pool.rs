@@ -0,0 +1,321 @@
+use x86_64::{
+    structures::{
+        paging::{
+            FrameAllocator,
+            FrameDeallocator,
+            PhysFrame,
+            Size4KiB
+        }
+    },
+    PhysAddr,
+    VirtAddr
+};
+use crate::serial_println;
+use crate::serial_print;
+use crate::memory::{PoolStats, PageInfo};
+
+pub const MAX_ORDER : usize = 10; // the largest block is 2^10 frames (4MB)
+const NIL : u64 = 0xffff_ffff_ffff_ffff;
+const HEAD_BYTES : usize = 4096 - 8 * (8 + MAX_ORDER + 1); // the pool has to fit in one frame
+
+// stored in the first frame of every free block
+#[repr(C)]
+struct FreeBlock {
+    next : u64,
+    prev : u64,
+    order : u64
+}
+
+/// Buddy system frame pool. Free blocks of 2^order frames are kept in one doubly linked list per
+/// order, with the links stored inside the free blocks themselves. `heads` has a bit for each
+/// frame that starts a free block, which is what tells us if a buddy can be merged on free.
+#[repr(C)]
+pub struct BuddyFramePool {
+    start_frame : u64,
+    next : *mut BuddyFramePool,
+    frame_count : u64, // frames spanned by this pool
+    usable_frames : u64,
+    free_frames : u64,
+    allocations : u64,
+    deallocations : u64,
+    page_info : *mut PageInfo,
+    free_lists : [u64; MAX_ORDER + 1], // frame index of the first free block of each order
+    heads : [u8; HEAD_BYTES]
+}
+
+fn order_frames(order : usize) -> u64 {
+    1 << order
+}
+
+// smallest order whose blocks hold `count` frames
+fn order_of(count : u64) -> usize {
+    let mut order = 0;
+    while order <= MAX_ORDER && order_frames(order) < count {
+        order += 1;
+    }
+    order
+}
+
+#[allow(dead_code)]
+impl BuddyFramePool {
+    pub const FRAMES_PER_POOL : u64 = HEAD_BYTES as u64 * 8;
+
+    pub fn init(&mut self, start_frame : u64, frame_count : u64) {
+        self.start_frame = start_frame;
+        self.next = 0x0 as *mut BuddyFramePool;
+        self.frame_count = frame_count;
+        self.usable_frames = 0;
+        self.free_frames = 0;
+        self.allocations = 0;
+        self.deallocations = 0;
+        self.page_info = 0x0 as *mut PageInfo;
+        self.free_lists = [NIL; MAX_ORDER + 1];
+        self.heads = [0b0000_0000; HEAD_BYTES];
+    }
+
+    pub fn set_page_info(&mut self, addr : VirtAddr) {
+        self.page_info = addr.as_u64() as *mut PageInfo;
+        unsafe {
+            core::ptr::write_bytes(self.page_info, 0, self.frame_count as usize);
+        }
+    }
+
+    pub fn page_info(& self, index : u64) -> &'static mut PageInfo {
+        unsafe {
+            &mut *self.page_info.offset(index as isize)
+        }
+    }
+
+    pub fn get_start_frame(& self) -> u64 {
+        self.start_frame
+    }
+
+    pub fn get_next(&self) -> Option<& BuddyFramePool> {
+        unsafe {
+            if self.next as u64 == 0x0 {
+                None
+            } else {
+                Some(&(*self.next))
+            }
+        }
+    }
+
+    pub fn get_next_mut(&self) -> Option<&mut BuddyFramePool> {
+        unsafe {
+            if self.next as u64 == 0x0 {
+                None
+            } else {
+                Some(&mut (*self.next))
+            }
+        }
+    }
+
+    pub fn set_next(&mut self, next : &mut BuddyFramePool) {
+        self.next = &mut (*next)
+    }
+
+    fn frame_at(& self, index : u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(self.start_frame + crate::machine::PAGE_SIZE * index))
+    }
+
+    fn block(& self, index : u64) -> &'static mut FreeBlock {
+        let addr = crate::memory::phys_to_virt(self.frame_at(index).start_address());
+        unsafe { &mut *(addr.as_u64() as *mut FreeBlock) }
+    }
+
+    fn is_head(& self, index : u64) -> bool {
+        let mask : u8 = 0b1000_0000 >> (index % 8) as u8;
+        self.heads[(index / 8) as usize] & mask != 0
+    }
+
+    fn set_head(&mut self, index : u64, head : bool) {
+        let mask : u8 = 0b1000_0000 >> (index % 8) as u8;
+        if head {
+            self.heads[(index / 8) as usize] |= mask;
+        } else {
+            self.heads[(index / 8) as usize] &= !mask;
+        }
+    }
+
+    fn push(&mut self, index : u64, order : usize) {
+        let head = self.free_lists[order];
+        {
+            let block = self.block(index);
+            block.next = head;
+            block.prev = NIL;
+            block.order = order as u64;
+        }
+        if head != NIL {
+            self.block(head).prev = index;
+        }
+        self.free_lists[order] = index;
+        self.set_head(index, true);
+    }
+
+    fn remove(&mut self, index : u64, order : usize) {
+        let (next, prev) = {
+            let block = self.block(index);
+            (block.next, block.prev)
+        };
+        if prev == NIL {
+            self.free_lists[order] = next;
+        } else {
+            self.block(prev).next = next;
+        }
+        if next != NIL {
+            self.block(next).prev = prev;
+        }
+        self.set_head(index, false);
+    }
+
+    // takes the smallest free block that fits and splits it down to the wanted order
+    fn allocate_block(&mut self, order : usize) -> Option<u64> {
+        let mut current = order;
+        while current <= MAX_ORDER && self.free_lists[current] == NIL {
+            current += 1;
+        }
+        if current > MAX_ORDER {
+            return None;
+        }
+
+        let index = self.free_lists[current];
+        self.remove(index, current);
+        while current > order {
+            current -= 1;
+            self.push(index + order_frames(current), current);
+        }
+        Some(index)
+    }
+
+    // puts a block back, merging it with its buddy for as long as the buddy is free too
+    fn free_block(&mut self, mut index : u64, mut order : usize) {
+        while order < MAX_ORDER {
+            let buddy = index ^ order_frames(order);
+            if buddy + order_frames(order) > BuddyFramePool::FRAMES_PER_POOL
+                || !self.is_head(buddy)
+                || self.block(buddy).order != order as u64 {
+                break;
+            }
+            self.remove(buddy, order);
+            index &= !order_frames(order);
+            order += 1;
+        }
+        self.push(index, order);
+    }
+
+    // frees [start_frame, end_frame) as a run of the largest aligned blocks that fit
+    pub fn mark_free(&mut self, mut start_frame : u64, end_frame : u64) {
+        if start_frame < end_frame {
+            self.free_frames += end_frame - start_frame;
+        }
+        while start_frame < end_frame {
+            let mut order = 0;
+            while order < MAX_ORDER
+                && start_frame % order_frames(order + 1) == 0
+                && start_frame + order_frames(order + 1) <= end_frame {
+                order += 1;
+            }
+            self.free_block(start_frame, order);
+            start_frame += order_frames(order);
+        }
+    }
+
+    // blocks are aligned relative to start_frame, so big alignments need an aligned pool
+    pub fn find_free_frames(&mut self, count : u64, align : u64) -> Option<PhysFrame> {
+        let align = if align < crate::machine::PAGE_SIZE { crate::machine::PAGE_SIZE } else { align };
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let align_frames = align / crate::machine::PAGE_SIZE;
+        if (self.start_frame / crate::machine::PAGE_SIZE) % align_frames != 0 {
+            return None;
+        }
+
+        let order = core::cmp::max(order_of(count), order_of(align_frames));
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let index = self.allocate_block(order)?;
+        for i in index..(index + count) {
+            self.page_info(i).ref_count = 1;
+        }
+        self.free_frames -= order_frames(order);
+        self.allocations += 1;
+        // hand back the tail of the block that wasn't asked for
+        self.mark_free(index + count, index + order_frames(order));
+        Some(self.frame_at(index))
+    }
+
+    pub fn find_free_frame(&mut self) -> Option<PhysFrame> {
+        let index = self.allocate_block(0)?;
+        self.page_info(index).ref_count = 1;
+        self.free_frames -= 1;
+        self.allocations += 1;
+        Some(self.frame_at(index))
+    }
+
+    // adds frames the pool can hand out, only used while setting up
+    pub fn add_frames(&mut self, start_frame : u64, end_frame : u64) {
+        self.usable_frames += end_frame - start_frame;
+        self.mark_free(start_frame, end_frame);
+    }
+
+    pub fn release_frames(&mut self, start_frame : u64, count : u64) {
+        self.deallocations += 1;
+        self.mark_free(start_frame, start_frame + count);
+    }
+
+    pub fn stats(& self) -> PoolStats {
+        // free blocks that follow each other form one run even if they can't be merged
+        let mut largest_free_run = 0;
+        let mut run = 0;
+        let mut index = 0;
+        while index < self.frame_count {
+            if self.is_head(index) {
+                let size = order_frames(self.block(index).order as usize);
+                run += size;
+                largest_free_run = core::cmp::max(largest_free_run, run);
+                index += size;
+            } else {
+                run = 0;
+                index += 1;
+            }
+        }
+
+        PoolStats {
+            total_frames : self.frame_count,
+            free_frames : self.free_frames,
+            used_frames : self.usable_frames - self.free_frames,
+            reserved_frames : self.frame_count - self.usable_frames,
+            largest_free_run,
+            allocations : self.allocations,
+            deallocations : self.deallocations
+        }
+    }
+
+    pub fn print_frame_map(& self) {
+        for order in 0..(MAX_ORDER + 1) {
+            let mut blocks = 0;
+            let mut index = self.free_lists[order];
+            while index != NIL {
+                blocks += 1;
+                index = self.block(index).next;
+            }
+            serial_print!("{}:{} ", order, blocks);
+        }
+        serial_println!("");
+    }
+}
+
+impl FrameAllocator<Size4KiB> for BuddyFramePool {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.find_free_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFramePool {
+    fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        crate::memory::free_frame(frame);
+    }
+}
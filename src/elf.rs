@@ -0,0 +1,183 @@
+use x86_64::VirtAddr;
+use crate::vm_pool::{VMPool, VMError, Protection};
+
+const ELF_MAGIC : [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_CLASS_64 : u8 = 2;
+const ELF_DATA_LSB : u8 = 1;
+const ET_EXEC : u16 = 2;
+const EM_X86_64 : u16 = 0x3e;
+const PT_LOAD : u32 = 1;
+const PF_X : u32 = 1;
+const PF_W : u32 = 2;
+const PF_R : u32 = 4;
+const HEADER_SIZE : usize = 64;
+const PROGRAM_HEADER_SIZE : usize = 56;
+
+/// Why an image could not be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    BadMagic,
+    Unsupported, // not a little endian x86_64 ELF64 executable
+    Truncated, // a header or segment points past the end of the image
+    BadSegment, // a segment shares a page with another one or lies outside the VMPool
+    VM(VMError)
+}
+
+// the fields of the ELF header and program headers the loader needs
+struct ProgramHeader {
+    p_type : u32,
+    flags : u32,
+    offset : u64,
+    vaddr : u64,
+    file_size : u64,
+    mem_size : u64
+}
+
+// offsets come from the image, one that doesn't fit in a usize points past its end as well
+fn offset(at : usize, by : usize) -> Result<usize, ElfError> {
+    at.checked_add(by).ok_or(ElfError::Truncated)
+}
+
+fn read_u16(image : &[u8], at : usize) -> Result<u16, ElfError> {
+    let bytes = image.get(at..offset(at, 2)?).ok_or(ElfError::Truncated)?;
+    Ok(u16::from(bytes[0]) | u16::from(bytes[1]) << 8)
+}
+
+fn read_u32(image : &[u8], at : usize) -> Result<u32, ElfError> {
+    Ok(u32::from(read_u16(image, at)?) | u32::from(read_u16(image, offset(at, 2)?)?) << 16)
+}
+
+fn read_u64(image : &[u8], at : usize) -> Result<u64, ElfError> {
+    Ok(u64::from(read_u32(image, at)?) | u64::from(read_u32(image, offset(at, 4)?)?) << 32)
+}
+
+fn read_program_header(image : &[u8], at : usize) -> Result<ProgramHeader, ElfError> {
+    Ok(ProgramHeader {
+        p_type : read_u32(image, at)?,
+        flags : read_u32(image, offset(at, 4)?)?,
+        offset : read_u64(image, offset(at, 8)?)?,
+        vaddr : read_u64(image, offset(at, 16)?)?,
+        file_size : read_u64(image, offset(at, 32)?)?,
+        mem_size : read_u64(image, offset(at, 40)?)?
+    })
+}
+
+fn protection(flags : u32) -> Protection {
+    let mut prot = Protection::NONE;
+    if flags & PF_R != 0 {
+        prot = prot | Protection::READ;
+    }
+    if flags & PF_W != 0 {
+        prot = prot | Protection::WRITE;
+    }
+    if flags & PF_X != 0 {
+        prot = prot | Protection::EXECUTE;
+    }
+    prot
+}
+
+// programs are linked into the VMPool range, anything else can't be mapped
+fn in_vm_pool(addr : u64, size : u64) -> bool {
+    let pool_end = crate::machine::HEAP_START + crate::machine::HEAP_SIZE;
+    addr >= crate::machine::HEAP_START && addr < pool_end && size <= pool_end - addr
+}
+
+// the page aligned range a loadable segment takes, None for the others
+fn segment_pages(header : &ProgramHeader) -> Option<(VirtAddr, VirtAddr)> {
+    if header.p_type != PT_LOAD || header.mem_size == 0 {
+        return None;
+    }
+    Some((VirtAddr::new(header.vaddr).align_down(crate::machine::PAGE_SIZE),
+          VirtAddr::new(header.vaddr + header.mem_size).align_up(crate::machine::PAGE_SIZE)))
+}
+
+fn read_segment_header(image : &[u8], ph_offset : usize, index : usize) -> Result<ProgramHeader, ElfError> {
+    let at = index.checked_mul(PROGRAM_HEADER_SIZE).ok_or(ElfError::Truncated)?;
+    read_program_header(image, offset(ph_offset, at)?)
+}
+
+// gives the segment its region, nothing is left of it if that fails
+fn load_segment(vm_pool : &mut VMPool, image : &[u8], header : &ProgramHeader) -> Result<(), ElfError> {
+    if header.p_type != PT_LOAD || header.mem_size == 0 {
+        return Ok(());
+    }
+    if header.file_size > header.mem_size {
+        return Err(ElfError::BadSegment);
+    }
+    let contents_end = header.offset.checked_add(header.file_size).ok_or(ElfError::Truncated)?;
+    let contents = image.get(header.offset as usize..contents_end as usize)
+        .ok_or(ElfError::Truncated)?;
+
+    if !in_vm_pool(header.vaddr, header.mem_size) {
+        return Err(ElfError::BadSegment);
+    }
+    let (start, end) = segment_pages(header).unwrap();
+    let size = (end.as_u64() - start.as_u64()) as usize;
+    // a page shared with another segment would need the protections of both
+    if vm_pool.get_region(start).is_some() || vm_pool.get_region(end - 1u64).is_some() {
+        return Err(ElfError::BadSegment);
+    }
+
+    // writable while it is filled in, the segment's own protection comes after
+    let region = vm_pool.allocate_at(start, size, Protection::READ | Protection::WRITE)
+        .map_err(ElfError::VM)?;
+    if region != start {
+        vm_pool.release(region);
+        return Err(ElfError::BadSegment);
+    }
+    unsafe {
+        core::ptr::write_bytes(start.as_u64() as *mut u8, 0, size);
+        core::ptr::copy_nonoverlapping(contents.as_ptr(), header.vaddr as *mut u8, contents.len());
+    }
+    if !vm_pool.protect(start, protection(header.flags)) {
+        vm_pool.release(start);
+        return Err(ElfError::VM(VMError::NoRegion));
+    }
+    Ok(())
+}
+
+/// Loads the PT_LOAD segments of `image` into `vm_pool`, which has to be the VMPool of the
+/// address space that is loaded right now. Every segment gets a region at its link address,
+/// filled from the image, zeroed past the file contents and protected as its flags say.
+/// Returns the entry point. If a segment can't be loaded, the ones before it are released.
+pub fn load(vm_pool : &mut VMPool, image : &[u8]) -> Result<VirtAddr, ElfError> {
+    if image.len() < HEADER_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if image[0..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    if image[4] != ELF_CLASS_64 || image[5] != ELF_DATA_LSB
+        || read_u16(image, 16)? != ET_EXEC || read_u16(image, 18)? != EM_X86_64 {
+        return Err(ElfError::Unsupported);
+    }
+
+    let entry = read_u64(image, 24)?;
+    if !in_vm_pool(entry, 1) {
+        return Err(ElfError::BadSegment);
+    }
+    let ph_offset = read_u64(image, 32)? as usize;
+    let ph_size = read_u16(image, 54)? as usize;
+    let ph_count = read_u16(image, 56)? as usize;
+    if ph_size != PROGRAM_HEADER_SIZE {
+        return Err(ElfError::Unsupported);
+    }
+
+    for i in 0..ph_count {
+        let result = read_segment_header(image, ph_offset, i)
+            .and_then(|header| load_segment(vm_pool, image, &header));
+        if let Err(error) = result {
+            // the headers before it were read and their segments loaded once already
+            for j in 0..i {
+                if let Ok(header) = read_segment_header(image, ph_offset, j) {
+                    if let Some((start, _)) = segment_pages(&header) {
+                        vm_pool.release(start);
+                    }
+                }
+            }
+            return Err(error);
+        }
+    }
+
+    Ok(VirtAddr::new(entry))
+}
@@ -4,14 +4,23 @@ use x86_64::{
         tss::TaskStateSegment,
         gdt::{GlobalDescriptorTable, Descriptor},
         gdt::SegmentSelector
-    }
+    },
+    PrivilegeLevel
 };
 use lazy_static::lazy_static;
+use core::cell::UnsafeCell;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+// The cpu reads the TSS while it is loaded and set_kernel_stack changes it, so it is only
+// ever reached through the pointer the cell hands out.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
             const STACK_SIZE: usize = 4096;
@@ -21,7 +30,18 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
-        tss
+        // A stack overflow faults on the guard page, the handler can't push onto that stack.
+        // There is only this one, so the handler must not fault itself, and it may only switch
+        // away from it for good, when it kills the process.
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096 * 4;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            let stack_end = stack_start + STACK_SIZE;
+            stack_end
+        };
+        Tss(UnsafeCell::new(tss))
     };
 }
 
@@ -29,14 +49,22 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
+        // the descriptor only takes its address, no reference to it outlives this
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
+        // ring 3 selectors have to ask for ring 3 themselves
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_data_selector = SegmentSelector::new(user_data_selector.index(), PrivilegeLevel::Ring3);
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let user_code_selector = SegmentSelector::new(user_code_selector.index(), PrivilegeLevel::Ring3);
+        (gdt, Selectors { code_selector, tss_selector, user_code_selector, user_data_selector })
     };
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
 }
 
 pub fn init() {
@@ -56,4 +84,21 @@ pub fn init() {
 
 pub fn get_cs() -> u64 {
     u64::from(GDT.1.code_selector.0)
-}
\ No newline at end of file
+}
+
+pub fn get_user_cs() -> u64 {
+    u64::from(GDT.1.user_code_selector.0)
+}
+
+pub fn get_user_ss() -> u64 {
+    u64::from(GDT.1.user_data_selector.0)
+}
+
+/// Sets the stack the cpu switches to when an interrupt comes in while ring 3 code runs.
+/// Every process running in ring 3 has its own, this is updated whenever one is switched to.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    // the cpu only reads it on the way out of ring 3, which can't happen while this runs
+    unsafe {
+        (*TSS.0.get()).privilege_stack_table[0] = stack_top;
+    }
+}
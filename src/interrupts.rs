@@ -2,24 +2,35 @@ use x86_64::{
     structures::{
         idt::{
             InterruptDescriptorTable,
-            ExceptionStackFrame
+            ExceptionStackFrame,
+            HandlerFunc
         }
-    }
+    },
+    PrivilegeLevel
 };
 use crate::{
     println,
     print,
     gdt,
-    process_table::page_fault_handler
+    process_table::{self, page_fault_handler}
 };
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
 use spin;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub const TIMER_INTERRUPT_ID: u8 = PIC_1_OFFSET;
 pub const KEYBOARD_INTERRUPT_ID: u8 = PIC_1_OFFSET + 1; // new
+pub const SYSCALL_INTERRUPT_ID: u8 = 0x80;
+
+// timer interrupts since boot
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst) as u64
+}
 
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
@@ -37,9 +48,65 @@ extern "x86-interrupt" fn double_fault_handler(
     crate::hlt_loop();
 }
 
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: &mut ExceptionStackFrame, error_code: u64)
+{
+    // privileged instructions and bad selectors in ring 3 only take down that process
+    if process_table::from_user_mode(stack_frame) {
+        process_table::kill(format_args!("general protection fault at {:?}", stack_frame.instruction_pointer));
+    }
+    // a non canonical user address in a copy
+    if let Some(fixup) = crate::user_memory::fixup_address(stack_frame.instruction_pointer) {
+        unsafe {
+            core::ptr::write_volatile(&mut stack_frame.instruction_pointer, fixup);
+        }
+        return;
+    }
+    println!("EXCEPTION: GENERAL PROTECTION FAULT {:#x}\n{:#?}", error_code, stack_frame);
+    crate::hlt_loop();
+}
+
+// Faults ring 3 code can cause by itself only take down its process, in the kernel they
+// are bugs like any other fault.
+fn fault(stack_frame : & ExceptionStackFrame, name : &str, error_code : Option<u64>) -> ! {
+    if process_table::from_user_mode(stack_frame) {
+        process_table::kill(format_args!("{} at {:?}", name, stack_frame.instruction_pointer));
+    }
+    match error_code {
+        Some(error_code) => println!("EXCEPTION: {} {:#x}\n{:#?}", name, error_code, stack_frame),
+        None => println!("EXCEPTION: {}\n{:#?}", name, stack_frame)
+    }
+    crate::hlt_loop();
+}
+
+macro_rules! fault_handler {
+    ($handler:ident, $name:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: &mut ExceptionStackFrame) {
+            fault(stack_frame, $name, None);
+        }
+    };
+    ($handler:ident, $name:expr, error_code) => {
+        extern "x86-interrupt" fn $handler(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+            fault(stack_frame, $name, Some(error_code));
+        }
+    };
+}
+
+fault_handler!(divide_by_zero_handler, "DIVIDE ERROR");
+fault_handler!(overflow_handler, "OVERFLOW");
+fault_handler!(bound_range_exceeded_handler, "BOUND RANGE EXCEEDED");
+fault_handler!(invalid_opcode_handler, "INVALID OPCODE");
+fault_handler!(device_not_available_handler, "DEVICE NOT AVAILABLE");
+fault_handler!(segment_not_present_handler, "SEGMENT NOT PRESENT", error_code);
+fault_handler!(stack_segment_fault_handler, "STACK SEGMENT FAULT", error_code);
+fault_handler!(x87_floating_point_handler, "X87 FLOATING POINT");
+fault_handler!(alignment_check_handler, "ALIGNMENT CHECK", error_code);
+fault_handler!(simd_floating_point_handler, "SIMD FLOATING POINT");
+
 extern "x86-interrupt" fn timer_interrupt_handler(
     _stack_frame: &mut ExceptionStackFrame)
 {
+    TICKS.fetch_add(1, Ordering::SeqCst);
     print!(".");
     unsafe {
         PICS.lock().notify_end_of_interrupt(TIMER_INTERRUPT_ID);
@@ -78,8 +145,20 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.divide_by_zero.set_handler_fn(divide_by_zero_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
         unsafe {
+            idt.page_fault.set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
@@ -89,6 +168,14 @@ lazy_static! {
         idt[usize::from(KEYBOARD_INTERRUPT_ID)]
             .set_handler_fn(keyboard_interrupt_handler);
 
+        // the entry saves the registers itself, ring 3 is allowed to raise it
+        let syscall_entry: HandlerFunc = unsafe {
+            core::mem::transmute(crate::syscall::syscall_entry as extern "C" fn())
+        };
+        idt[usize::from(SYSCALL_INTERRUPT_ID)]
+            .set_handler_fn(syscall_entry)
+            .set_privilege_level(PrivilegeLevel::Ring3);
+
         idt
     };
 }
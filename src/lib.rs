@@ -2,7 +2,13 @@
 #![cfg_attr(not(test), no_std)] // don't link the Rust standard library
 #![feature(abi_x86_interrupt)]
 #![feature(asm)]
+#![feature(global_asm)]
 #![feature(naked_functions)]
+#![feature(alloc)]
+#![feature(alloc_error_handler)]
+#![feature(try_reserve)]
+
+extern crate alloc;
 
 pub mod machine;
 pub mod vga_buffer;
@@ -10,8 +16,15 @@ pub mod serial;
 pub mod interrupts;
 pub mod gdt;
 pub mod memory;
+pub mod buddy_pool;
+pub mod allocator;
+pub mod slab;
 pub mod process_table;
 pub mod vm_pool;
+pub mod shared_memory;
+pub mod elf;
+pub mod syscall;
+pub mod user_memory;
 pub mod scheduler;
 
 pub unsafe fn exit_qemu() {
@@ -22,6 +35,12 @@ pub unsafe fn exit_qemu() {
 }
 
 
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    panic!("allocation error: {:?}", layout)
+}
+
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
@@ -6,8 +6,15 @@ pub const PAGE_SIZE: u64 = 0o1_0000; // 4KB
 pub const PAGE_OFFSET_BITS : u8 = 12;
 pub const HEAP_START : u64 = 0o1_000_000_0000;
 pub const HEAP_SIZE : u64 = 0o1_000_000_0000;
+pub const KERNEL_HEAP_SIZE : u64 = 0o1_000_000; // 256KB
+pub const STACK_SIZE : u64 = 0o20_000; // 8KB
+pub const STACK_LIMIT : u64 = 0o400_000; // 64KB, how far a stack may grow
+pub const KERNEL_STACK_SIZE : u64 = 0o20_000; // 8KB, what a ring 3 process runs on in the kernel
 pub const L4_PAGE_TABLE_VADDR : u64 = 0o1_77777_777_777_777_777_0000;
 pub const L3_PAGE_TABLE_VADDR : u64 = 0o1_77777_777_777_777_000_0000;
 pub const L2_PAGE_TABLE_VADDR : u64 = 0o1_77777_777_777_000_000_0000;
+pub const PHYS_MAP_START : u64 = 0o1_77777_400_000_000_000_0000; // all of physical memory is mapped here
 
-pub type CFunc = extern "C" fn();
\ No newline at end of file
+pub type CFunc = extern "C" fn();
+pub type CFuncArg = extern "C" fn(u64);
+pub type CFuncArgs = extern "C" fn(u64, u64, u64, u64, u64, u64);
\ No newline at end of file
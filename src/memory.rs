@@ -7,6 +7,8 @@ use x86_64::{
             Size4KiB
         }
     },
+    registers::control::{Cr0, Cr0Flags},
+    registers::model_specific::{Efer, EferFlags},
     VirtAddr,
     PhysAddr
 };
@@ -16,21 +18,105 @@ use crate::serial_println;
 use crate::serial_print;
 use x86_64::structures::paging::FrameDeallocator;
 
-static mut SYSTEM_FRAME_POOL : *mut SimpleFramePool = (0x0 as *mut SimpleFramePool);
-static mut USER_FRAME_POOL : *mut SimpleFramePool = (0x0 as *mut SimpleFramePool);
+/// The frame pool implementation backing the system and user pools. The bitmap pool is the
+/// default, the `buddy-frame-pool` feature swaps in `buddy_pool::BuddyFramePool`.
+#[cfg(not(feature = "buddy-frame-pool"))]
+pub type FramePool = SimpleFramePool;
+#[cfg(feature = "buddy-frame-pool")]
+pub type FramePool = crate::buddy_pool::BuddyFramePool;
 
-pub struct SimpleFramePool { // manages frames from i (510*8*8) till i+1 (510*8*8)
+static mut SYSTEM_FRAME_POOL : *mut FramePool = (0x0 as *mut FramePool);
+static mut USER_FRAME_POOL : *mut FramePool = (0x0 as *mut FramePool);
+
+const BITMAP_BYTES : usize = 4032;
+
+/// Bookkeeping for one frame. Every pool keeps an array of these, indexed by the frame's number
+/// within the pool, in frames at the start of the range it manages.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PageInfo {
+    pub ref_count : u16
+}
+
+// frames needed for the PageInfo array of a pool spanning `frame_count` frames
+fn page_info_frames(frame_count : u64) -> u64 {
+    let bytes = frame_count * core::mem::size_of::<PageInfo>() as u64;
+    (bytes + crate::machine::PAGE_SIZE - 1) / crate::machine::PAGE_SIZE
+}
+
+/// Frame counts of one pool, or summed up over a chain of pools.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoolStats {
+    pub total_frames : u64, // frames in the address range the pools span
+    pub free_frames : u64,
+    pub used_frames : u64,
+    pub reserved_frames : u64, // never usable: holes, the kernel image and the pools themselves
+    pub largest_free_run : u64, // longest run of contiguous free frames
+    pub allocations : u64,
+    pub deallocations : u64
+}
+
+impl PoolStats {
+    fn add(&mut self, other : PoolStats) {
+        self.total_frames += other.total_frames;
+        self.free_frames += other.free_frames;
+        self.used_frames += other.used_frames;
+        self.reserved_frames += other.reserved_frames;
+        self.largest_free_run = core::cmp::max(self.largest_free_run, other.largest_free_run);
+        self.allocations += other.allocations;
+        self.deallocations += other.deallocations;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    pub system : PoolStats,
+    pub user : PoolStats
+}
+
+pub struct SimpleFramePool { // manages frames from i (504*8*8) till i+1 (504*8*8)
     start_frame: u64,
     next : *mut SimpleFramePool,
-    frames: [u8; 4080] // because 8 u8s are gone for the start frame
+    frame_count : u64, // frames spanned by this pool
+    usable_frames : u64,
+    free_frames : u64,
+    allocations : u64,
+    deallocations : u64,
+    page_info : *mut PageInfo,
+    frames: [u8; BITMAP_BYTES] // because 64 u8s are gone for the header
 }
 
 #[allow(dead_code)]
 impl SimpleFramePool {
-    fn init(&mut self, start_frame : u64) {
+    pub const FRAMES_PER_POOL : u64 = BITMAP_BYTES as u64 * 8;
+
+    fn init(&mut self, start_frame : u64, frame_count : u64) {
         self.start_frame = start_frame;
-        self.frames = [0b0000_0000; 4080];
+        self.frames = [0b0000_0000; BITMAP_BYTES];
         self.next = 0x0 as *mut SimpleFramePool;
+        self.frame_count = frame_count;
+        self.usable_frames = 0;
+        self.free_frames = 0;
+        self.allocations = 0;
+        self.deallocations = 0;
+        self.page_info = 0x0 as *mut PageInfo;
+    }
+
+    fn set_page_info(&mut self, addr : VirtAddr) {
+        self.page_info = addr.as_u64() as *mut PageInfo;
+        unsafe {
+            core::ptr::write_bytes(self.page_info, 0, self.frame_count as usize);
+        }
+    }
+
+    fn page_info(& self, frame_no : u64) -> &'static mut PageInfo {
+        unsafe {
+            &mut *self.page_info.offset(frame_no as isize)
+        }
+    }
+
+    fn get_start_frame(& self) -> u64 {
+        self.start_frame
     }
 
     fn get_next(&self) -> Option<& SimpleFramePool> {
@@ -100,6 +186,7 @@ impl SimpleFramePool {
     }
 
     fn mark_free(&mut self, mut start_frame : u64, end_frame : u64) {
+        self.free_frames += end_frame - start_frame;
         let end_byte = end_frame / 8;
         while start_frame < end_frame {
             let start_byte = start_frame / 8;
@@ -115,6 +202,7 @@ impl SimpleFramePool {
     }
 
     fn mark_used(&mut self, mut start_frame : u64, end_frame : u64) {
+        self.free_frames -= end_frame - start_frame;
         let end_byte = end_frame / 8;
         while start_frame < end_frame {
             let start_byte = start_frame / 8;
@@ -129,37 +217,106 @@ impl SimpleFramePool {
         }
     }
 
+    fn is_free(& self, frame_no : u64) -> bool {
+        let mask : u8 = 0b1000_0000 >> (frame_no % 8) as u8;
+        self.frames[(frame_no / 8) as usize] & mask != 0
+    }
+
+    // returns the first used frame in [start_frame, end_frame) if there is one
+    fn first_used_frame(& self, mut start_frame : u64, end_frame : u64) -> Option<u64> {
+        while start_frame < end_frame {
+            if start_frame % 8 == 0 && start_frame + 8 <= end_frame && self.frames[(start_frame / 8) as usize] == 0b1111_1111 {
+                start_frame += 8;
+                continue;
+            }
+            if !self.is_free(start_frame) {
+                return Some(start_frame);
+            }
+            start_frame += 1;
+        }
+        None
+    }
+
+    // finds `count` contiguous free frames whose physical start address is a multiple of `align` bytes
+    fn find_free_frames(&mut self, count : u64, align : u64) -> Option<PhysFrame> {
+        let align = if align < crate::machine::PAGE_SIZE { crate::machine::PAGE_SIZE } else { align };
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let total_frames = self.frames.len() as u64 * 8;
+        let align_frames = align / crate::machine::PAGE_SIZE;
+        let first_aligned = ((align - self.start_frame % align) % align) / crate::machine::PAGE_SIZE;
+
+        let mut i = first_aligned;
+        while i + count <= total_frames {
+            match self.first_used_frame(i, i + count) {
+                None => {
+                    self.mark_used(i, i + count);
+                    for j in i..(i + count) {
+                        self.page_info(j).ref_count = 1;
+                    }
+                    self.allocations += 1;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(self.start_frame + crate::machine::PAGE_SIZE * i)));
+                },
+                Some(used) => {
+                    // skip to the next aligned candidate past the used frame
+                    i = first_aligned + ((used + 1 - first_aligned + align_frames - 1) / align_frames) * align_frames;
+                }
+            }
+        }
+
+        None
+    }
+
     fn find_free_frame(&mut self) -> Option<PhysFrame> {
-        for i in 0..4080 {
+        for i in 0..BITMAP_BYTES {
             let _byte : u8 = self.frames[i];
             if SimpleFramePool::is_full_block(_byte) {
                 continue;
             }
             let offset = SimpleFramePool::get_first_free_block(_byte);
             self.mark_used(i as u64 * 8 + offset as u64, i as u64 * 8 + offset as u64 + 1);
+            self.page_info(i as u64 * 8 + offset as u64).ref_count = 1;
+            self.allocations += 1;
             return Some(PhysFrame::containing_address(PhysAddr::new(self.start_frame + crate::machine::PAGE_SIZE * (i as u64 * 8 + offset as u64))));
         };
 
         return None;
     }
 
-    fn free_frame(frame : PhysFrame) {
-        let frame_addr : u64 = frame.start_address().as_u64();
-        let mut fp = get_frame_pool_mut(true);
-        loop {
-            if fp.get_next_mut().is_none() {
-                fp.mark_free((frame_addr - fp.start_frame) / crate::machine::PAGE_SIZE, (frame_addr - fp.start_frame) / crate::machine::PAGE_SIZE + 1);
-                break
-            }
 
-            if  fp.get_next_mut().unwrap().start_frame > frame_addr {
-                fp.mark_free((frame_addr - fp.start_frame) / crate::machine::PAGE_SIZE, (frame_addr - fp.start_frame) / crate::machine::PAGE_SIZE + 1);
-                break
+    // adds frames the pool can hand out, only used while setting up
+    fn add_frames(&mut self, start_frame : u64, end_frame : u64) {
+        self.usable_frames += end_frame - start_frame;
+        self.mark_free(start_frame, end_frame);
+    }
+
+    fn release_frames(&mut self, start_frame : u64, count : u64) {
+        self.deallocations += 1;
+        self.mark_free(start_frame, start_frame + count);
+    }
+
+    fn stats(& self) -> PoolStats {
+        let mut largest_free_run = 0;
+        let mut run = 0;
+        for i in 0..self.frame_count {
+            if self.is_free(i) {
+                run += 1;
+                largest_free_run = core::cmp::max(largest_free_run, run);
             } else {
-                fp = fp.get_next_mut().unwrap();
+                run = 0;
             }
         }
 
+        PoolStats {
+            total_frames : self.frame_count,
+            free_frames : self.free_frames,
+            used_frames : self.usable_frames - self.free_frames,
+            reserved_frames : self.frame_count - self.usable_frames,
+            largest_free_run,
+            allocations : self.allocations,
+            deallocations : self.deallocations
+        }
     }
 
     fn print_frame_map(& self) {
@@ -179,7 +336,7 @@ impl FrameAllocator<Size4KiB> for SimpleFramePool {
 
 impl FrameDeallocator<Size4KiB> for SimpleFramePool {
     fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        SimpleFramePool::free_frame(frame);
+        free_frame(frame);
     }
 }
 
@@ -196,12 +353,117 @@ pub fn get_frame(kernel: bool, raw: bool) -> Option<PhysFrame> {
             Some(PhysFrame::containing_address(PhysAddr::new(virt_addr.as_u64())))
         }
     } else {
-        get_frame_pool_mut(false).allocate_frame()
+        let mut fp = get_frame_pool_mut(false);
+        loop {
+            let frame = fp.allocate_frame();
+            if frame.is_some() {
+                return frame;
+            }
+            match fp.get_next_mut() {
+                Some(next) => fp = next,
+                None => return None
+            }
+        }
+    }
+}
+
+/// Allocates `count` physically contiguous frames starting at a multiple of `align` bytes.
+/// Kernel frames come from the system pool, user frames from the first pool in the user chain
+/// that can satisfy the request. The frames have to be released together with `free_frames`.
+pub fn get_frames(kernel: bool, raw: bool, count: u64, align: u64) -> Option<PhysFrame> {
+    if kernel {
+        let frame = get_frame_pool_mut(true).find_free_frames(count, align);
+        if frame.is_none() {
+            return None;
+        };
+        if raw {
+            frame
+        } else {
+            let virt_addr = transform_kernel_to_vir(frame.unwrap().start_address());
+            Some(PhysFrame::containing_address(PhysAddr::new(virt_addr.as_u64())))
+        }
+    } else {
+        let mut fp = get_frame_pool_mut(false);
+        loop {
+            let frame = fp.find_free_frames(count, align);
+            if frame.is_some() {
+                return frame;
+            }
+            match fp.get_next_mut() {
+                Some(next) => fp = next,
+                None => return None
+            }
+        }
     }
 }
 
+// the pool chain is sorted by start frame, so the owner is the last pool starting at or before addr
+fn get_owner_pool_mut(addr : u64) -> &'static mut FramePool {
+    let mut fp = get_frame_pool_mut(true);
+    loop {
+        if fp.get_next_mut().is_none() || fp.get_next_mut().unwrap().get_start_frame() > addr {
+            return fp;
+        }
+        fp = fp.get_next_mut().unwrap();
+    }
+}
+
+fn get_page_info(frame : PhysFrame) -> &'static mut PageInfo {
+    let frame_addr : u64 = frame.start_address().as_u64();
+    let fp = get_owner_pool_mut(frame_addr);
+    fp.page_info((frame_addr - fp.get_start_frame()) / crate::machine::PAGE_SIZE)
+}
+
+/// Number of references held on a (raw physical) frame, 0 for free frames.
+pub fn frame_ref_count(frame : PhysFrame) -> u16 {
+    get_page_info(frame).ref_count
+}
+
+/// Takes another reference on an allocated frame, e.g. to map it into a second address space.
+pub fn get_frame_ref(frame : PhysFrame) -> u16 {
+    let page_info = get_page_info(frame);
+    page_info.ref_count += 1;
+    page_info.ref_count
+}
+
+/// Drops a reference on a frame and releases it to its pool once nobody holds it anymore.
+pub fn put_frame_ref(frame : PhysFrame) -> u16 {
+    free_frames(frame, 1);
+    frame_ref_count(frame)
+}
+
 pub fn free_frame(frame : PhysFrame) {
-    SimpleFramePool::free_frame(frame)
+    free_frames(frame, 1)
+}
+
+/// Drops a reference on each of the `count` contiguous frames starting at `frame` (a raw
+/// physical frame). Frames whose count drops to zero go back to the pool. Freeing a frame
+/// nobody holds is a bug, it would be handed out twice.
+pub fn free_frames(frame : PhysFrame, count : u64) {
+    let frame_addr : u64 = frame.start_address().as_u64();
+    let fp = get_owner_pool_mut(frame_addr);
+    let start = (frame_addr - fp.get_start_frame()) / crate::machine::PAGE_SIZE;
+
+    // release the frames in runs of ones that lost their last reference
+    let mut run_start = start;
+    for i in start..(start + count) {
+        let page_info = fp.page_info(i);
+        if page_info.ref_count == 0 {
+            panic!("double free of frame {:#x}", fp.get_start_frame() + i * crate::machine::PAGE_SIZE);
+        }
+        if page_info.ref_count > 1 {
+            page_info.ref_count -= 1;
+            if run_start < i {
+                fp.release_frames(run_start, i - run_start);
+            }
+            run_start = i + 1;
+        } else {
+            page_info.ref_count = 0;
+        }
+    }
+    if run_start < start + count {
+        fp.release_frames(run_start, start + count - run_start);
+    }
 }
 
 #[allow(dead_code)]
@@ -209,11 +471,90 @@ fn print_frame_map() {
     get_frame_pool_mut(true).print_frame_map();
 }
 
+/// Frame counts of the system pool and of the whole chain of user pools.
+pub fn stats() -> MemoryStats {
+    let mut user = PoolStats::default();
+    let mut fp = get_frame_pool(true).get_next();
+    while let Some(pool) = fp {
+        user.add(pool.stats());
+        fp = pool.get_next();
+    }
+
+    MemoryStats {
+        system : get_frame_pool(true).stats(),
+        user
+    }
+}
+
+fn print_pool_stats(name : &str, pool : &PoolStats) {
+    serial_println!("{}: {} frames, {} free, {} used, {} reserved, largest free run {}, {} allocations, {} deallocations",
+        name, pool.total_frames, pool.free_frames, pool.used_frames, pool.reserved_frames,
+        pool.largest_free_run, pool.allocations, pool.deallocations);
+}
+
+/// Dumps the frame pool statistics to serial.
+pub fn print_stats() {
+    let stats = stats();
+    print_pool_stats("system", &stats.system);
+    print_pool_stats("user", &stats.user);
+}
+
 #[allow(dead_code)]
 pub fn transform_kernel_to_vir(addr: PhysAddr) -> VirtAddr {
     VirtAddr::new(addr.as_u64() - crate::machine::KERNEL_PHY_START + crate::machine::KERNEL_VIR_START)
 }
 
+#[allow(dead_code)]
+pub fn transform_kernel_to_phy(addr: VirtAddr) -> PhysAddr {
+    PhysAddr::new(addr.as_u64() - crate::machine::KERNEL_VIR_START + crate::machine::KERNEL_PHY_START)
+}
+
+/// Kernel virtual address through which the frame at `addr` can be read and written.
+/// System pool frames go through the kernel mapping (which exists before the direct map is
+/// built), everything else through the direct map of physical memory at PHYS_MAP_START.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    if addr.as_u64() >= crate::machine::KERNEL_PHY_START && addr.as_u64() < crate::machine::KERNEL_SPACE {
+        transform_kernel_to_vir(addr)
+    } else {
+        VirtAddr::new(addr.as_u64() + crate::machine::PHYS_MAP_START)
+    }
+}
+
+// maps physical memory [0, max_addr) at PHYS_MAP_START using 2MB pages
+fn init_phys_map(max_addr : u64) {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let l4_table : &mut PageTable = unsafe { &mut *(crate::machine::L4_PAGE_TABLE_VADDR as *mut PageTable)};
+    let p3_frame = get_frame(true, true).unwrap();
+    let p3_table : &mut PageTable = unsafe { &mut *(transform_kernel_to_vir(p3_frame.start_address()).as_u64() as *mut PageTable)};
+    p3_table.zero();
+
+    let gb : u64 = 0o1_000_000_0000;
+    let mb2 : u64 = 0o1_000_0000;
+    for i in 0..((max_addr + gb - 1) / gb) {
+        let p2_frame = get_frame(true, true).unwrap();
+        let p2_table : &mut PageTable = unsafe { &mut *(transform_kernel_to_vir(p2_frame.start_address()).as_u64() as *mut PageTable)};
+        for j in 0..512 {
+            p2_table[j].set_addr(PhysAddr::new(i * gb + j as u64 * mb2), Flags::PRESENT | Flags::WRITABLE | Flags::HUGE_PAGE);
+        }
+        p3_table[i as usize].set_addr(p2_frame.start_address(), Flags::PRESENT | Flags::WRITABLE);
+    }
+
+    let p4_index = ((crate::machine::PHYS_MAP_START >> 39) & 0o777) as usize;
+    l4_table[p4_index].set_addr(p3_frame.start_address(), Flags::PRESENT | Flags::WRITABLE);
+}
+
+// Copy-on-write pages are read-only, the kernel must fault on writing them too, and regions
+// without EXECUTE are mapped NO_EXECUTE, which the cpu only honours with NXE set.
+fn enable_page_protection() {
+    unsafe {
+        Cr0::write(Cr0::read() | Cr0Flags::WRITE_PROTECT);
+        Efer::write(Efer::read() | EferFlags::NO_EXECUTE_ENABLE);
+    }
+}
+
+/// Sets up paging for the kernel, including the cpu bits the page protections rely on, and
+/// the frame pools and the kernel heap on top of it.
 pub fn init_frame_allocator(
     memory_map: &'static MemoryMap) {
     use x86_64::structures::paging::PageTableFlags as Flags;
@@ -228,35 +569,84 @@ pub fn init_frame_allocator(
 
     let pt_p2 : &mut PageTable = unsafe { &mut *(0o1_77777_777_777_000_000_0000 as *mut PageTable)};
     pt_p2[511].set_addr(PhysAddr::new(0x2_00000), Flags::PRESENT | Flags::WRITABLE | Flags::HUGE_PAGE);
+    enable_page_protection();
 
     // get usable regions from memory map
     let regions = memory_map
         .iter()
         .filter(|r| r.region_type == MemoryRegionType::Usable);
 
-    // use address range less than 4 MB for kernel frame pool
+    // use address range between 2 MB and 4 MB for kernel frame pool
+    for region in regions.clone() {
+        let mut start = core::cmp::max(region.range.start_addr(), crate::machine::KERNEL_PHY_START);
+        let end = core::cmp::min(region.range.end_addr(), crate::machine::KERNEL_SPACE);
+        if start >= end {
+            continue;
+        }
+        if unsafe { SYSTEM_FRAME_POOL.is_null() } {
+            // the pool lives in the first frame it manages
+            let sys_frame_addr = transform_kernel_to_vir(PhysAddr::new(start));
+            set_system_frame_pool(sys_frame_addr.as_u64() as *mut FramePool);
+            let frame_count = (crate::machine::KERNEL_SPACE - crate::machine::KERNEL_PHY_START) / crate::machine::PAGE_SIZE;
+            get_frame_pool_mut(true).init(crate::machine::KERNEL_PHY_START, frame_count);
+            start += crate::machine::PAGE_SIZE;
+
+            // followed by its page info
+            get_frame_pool_mut(true).set_page_info(transform_kernel_to_vir(PhysAddr::new(start)));
+            start += page_info_frames(frame_count) * crate::machine::PAGE_SIZE;
+        }
+        get_frame_pool_mut(true).add_frames(
+            (start - crate::machine::KERNEL_PHY_START)/crate::machine::PAGE_SIZE,
+            (end - crate::machine::KERNEL_PHY_START)/crate::machine::PAGE_SIZE);
+    }
+
+    // the user pools may need to touch their frames, so the direct map has to exist first
+    let max_addr = regions.clone().map(|r| r.range.end_addr()).max().unwrap_or(0);
+    init_phys_map(max_addr);
+
+    // everything above 4 MB goes to the user pools
     for region in regions {
-        if region.range.end_addr() <= crate::machine::KERNEL_SPACE {
-            let sys_frame_addr = transform_kernel_to_vir(PhysAddr::new(region.range.start_addr()));
-            set_system_frame_pool(sys_frame_addr.as_u64() as *mut SimpleFramePool);
-            get_frame_pool_mut(true).init(crate::machine::KERNEL_PHY_START);
-            get_frame_pool_mut(true).mark_free(
-                (region.range.start_addr() - crate::machine::KERNEL_PHY_START)/crate::machine::PAGE_SIZE + 1,
-                (region.range.end_addr() - crate::machine::KERNEL_PHY_START)/crate::machine::PAGE_SIZE);
-        } else {
-            let user_frame_addr = get_frame(true, false).unwrap().start_address();
-            set_user_frame_pool(user_frame_addr.as_u64() as *mut SimpleFramePool);
-            get_frame_pool_mut(true).set_next(get_frame_pool_mut(false));
-            get_frame_pool_mut(false).init(crate::machine::KERNEL_SPACE);
-            get_frame_pool_mut(false).mark_free(
-                (region.range.start_addr() - crate::machine::KERNEL_SPACE)/crate::machine::PAGE_SIZE,
-                (region.range.end_addr() - crate::machine::KERNEL_SPACE)/crate::machine::PAGE_SIZE);
+        let start = core::cmp::max(region.range.start_addr(), crate::machine::KERNEL_SPACE);
+        let end = region.range.end_addr();
+        if start < end {
+            add_user_region(start, end);
         }
     }
+
+    crate::allocator::init_heap();
+}
+
+// covers [start, end) with as many user pools as it takes
+fn add_user_region(mut start : u64, end : u64) {
+    while start < end {
+        let pool_end = core::cmp::min(end, start + FramePool::FRAMES_PER_POOL * crate::machine::PAGE_SIZE);
+        let pool_addr = get_frame(true, false).unwrap().start_address();
+        let pool : &'static mut FramePool = unsafe { &mut *(pool_addr.as_u64() as *mut FramePool) };
+        let frame_count = (pool_end - start) / crate::machine::PAGE_SIZE;
+        pool.init(start, frame_count);
+        // the page info array takes the first frames of the range
+        pool.set_page_info(phys_to_virt(PhysAddr::new(start)));
+        pool.add_frames(core::cmp::min(page_info_frames(frame_count), frame_count), frame_count);
+        insert_user_pool(pool);
+        start = pool_end;
+    }
+}
+
+// links a pool into the chain behind the system pool, keeping the chain sorted by start frame
+fn insert_user_pool(pool : &'static mut FramePool) {
+    let fp = get_owner_pool_mut(pool.get_start_frame());
+    if let Some(next) = fp.get_next_mut() {
+        pool.set_next(next);
+    }
+    let first_user_pool = fp.get_start_frame() == get_frame_pool(true).get_start_frame();
+    fp.set_next(pool);
+    if first_user_pool {
+        set_user_frame_pool(pool);
+    }
 }
 
 #[allow(dead_code)]
-pub fn get_frame_pool(kernel : bool) -> &'static SimpleFramePool {
+pub fn get_frame_pool(kernel : bool) -> &'static FramePool {
     if kernel {
         unsafe {
             & (*SYSTEM_FRAME_POOL)
@@ -269,7 +659,7 @@ pub fn get_frame_pool(kernel : bool) -> &'static SimpleFramePool {
 }
 
 #[allow(dead_code)]
-pub fn get_frame_pool_mut(kernel : bool) -> &'static mut SimpleFramePool {
+pub fn get_frame_pool_mut(kernel : bool) -> &'static mut FramePool {
     if kernel {
         unsafe {
             &mut (*SYSTEM_FRAME_POOL)
@@ -282,14 +672,14 @@ pub fn get_frame_pool_mut(kernel : bool) -> &'static mut SimpleFramePool {
 }
 
 #[allow(dead_code)]
-fn set_system_frame_pool(fp : *mut SimpleFramePool) {
+fn set_system_frame_pool(fp : *mut FramePool) {
     unsafe {
         SYSTEM_FRAME_POOL = fp;
     }
 }
 
 #[allow(dead_code)]
-fn set_user_frame_pool(fp : *mut SimpleFramePool) {
+fn set_user_frame_pool(fp : *mut FramePool) {
     unsafe {
         USER_FRAME_POOL = fp;
     }
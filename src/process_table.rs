@@ -10,21 +10,35 @@ use x86_64::{
             PhysFrame,
             RecursivePageTable,
             PageTable,
+            PageTableEntry,
             PageTableFlags as Flags
         }
     },
+    instructions::tlb,
     VirtAddr,
     PhysAddr,
 };
 use crate::{
     println,
     serial_println,
-    vm_pool::VMPool
+    vm_pool::{VMPool, VMError, Protection},
+    slab::SlabCache,
+    elf::ElfError
 };
 use x86_64::structures::paging::{Mapper, Page};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 static mut CURR_PROCESS_TABLE: *mut MyProcess = 0x0 as *mut MyProcess;
 static mut NEXT_PROCESS: *mut MyProcess = 0x0 as *mut MyProcess;
+// processes that ended but still have their address space, linked through `next`
+static mut TERMINATED_PROCESSES: *mut MyProcess = 0x0 as *mut MyProcess;
+// processes blocked in `wait`
+static mut WAITING_PROCESSES: *mut MyProcess = 0x0 as *mut MyProcess;
 
 #[allow(dead_code)]
 pub fn get_curr_process_table() -> &'static MyProcess {
@@ -49,20 +63,102 @@ fn set_curr_process_table(pt : &mut MyProcess) {
 
 #[allow(dead_code)]
 pub fn set_next_process(pt : &mut MyProcess) {
+    // interrupts out of ring 3 have to land on the kernel stack of the process they interrupt
+    if pt.is_user() {
+        crate::gdt::set_kernel_stack(VirtAddr::new(pt.kernel_stack));
+    }
     unsafe {
         NEXT_PROCESS = &mut (*pt)
     }
 }
 
-static mut NEXT_PROCESS_ID : u16 = 0;
+static mut PROCESS_CACHE : SlabCache = SlabCache::new(core::mem::size_of::<MyProcess>() as u64);
+
+#[allow(dead_code)]
+pub fn get_process_cache() -> &'static SlabCache {
+    unsafe {
+        & PROCESS_CACHE
+    }
+}
+
+// marks shared pages that were writable before a fork, a write to them copies the frame
+const COPY_ON_WRITE : Flags = Flags::BIT_9;
+
+// what a process enters ring 3 with, interrupts enabled
+const USER_RFLAGS : u64 = 0x202;
+
+/// The exit code of a process killed for a fault in ring 3 or for overflowing its stack.
+pub const KILLED_EXIT_CODE : u64 = u64::max_value();
+
+/// What `fork` returns when no child could be created.
+pub const FORK_FAILED : u64 = u64::max_value();
+
+// every process from creation until its exit code is collected, by process id
+struct ProcessRegistry {
+    last_id : u16,
+    processes : BTreeMap<u16, *mut MyProcess>
+}
+
+// the processes themselves are only reached through the lock
+unsafe impl Send for ProcessRegistry {}
+
+lazy_static! {
+    static ref PROCESS_REGISTRY : Mutex<ProcessRegistry> = Mutex::new(ProcessRegistry {
+        last_id : 0,
+        processes : BTreeMap::new()
+    });
+
+    // how many threads use each address space, by the address of its PML4
+    static ref ADDRESS_SPACE_USERS : Mutex<BTreeMap<u64, u64>> = Mutex::new(BTreeMap::new());
+}
+
+// the next id after the last one handed out that no process holds, 0 is never used,
+// None when every id is held
+fn register_process(process : &mut MyProcess) -> Option<u16> {
+    let mut registry = PROCESS_REGISTRY.lock();
+    if registry.processes.len() >= u16::max_value() as usize {
+        return None;
+    }
+    loop {
+        registry.last_id = registry.last_id.wrapping_add(1);
+        if registry.last_id != 0 && !registry.processes.contains_key(&registry.last_id) {
+            break;
+        }
+    }
+    let pid = registry.last_id;
+    registry.processes.insert(pid, process);
+    Some(pid)
+}
+
+/// The process with id `pid`, zombies included.
+pub fn get_process(pid : u16) -> Option<&'static mut MyProcess> {
+    PROCESS_REGISTRY.lock().processes.get(&pid).map(|process| unsafe { &mut **process })
+}
+
+/// All processes by id, zombies included.
+pub fn processes() -> impl Iterator<Item = &'static MyProcess> {
+    let processes : Vec<*mut MyProcess> = PROCESS_REGISTRY.lock().processes.values().cloned().collect();
+    processes.into_iter().map(|process| unsafe { & *process })
+}
 
-fn faa_next_proc_id() -> u16 {
+pub fn print_processes() {
+    for process in processes() {
+        serial_println!("{} {:?} parent {} {:?}", process.process_id, process.name(), process.parent_id, process.state);
+    }
+}
+
+// the struct is the last thing that goes, its id is free for reuse after this
+fn release_process(process : &mut MyProcess) {
+    PROCESS_REGISTRY.lock().processes.remove(&process.process_id);
     unsafe {
-        NEXT_PROCESS_ID+=1;
-        NEXT_PROCESS_ID
+        PROCESS_CACHE.free(VirtAddr::new(process as *mut MyProcess as u64));
     }
 }
 
+/// A thread of execution, which is what the scheduler runs. A process is a thread with an
+/// address space of its own, the threads it starts share its page table and VMPool and have
+/// a stack of their own in it. Every thread has its own id, `thread_group` is the id of the
+/// process it belongs to.
 #[repr(C)]
 #[derive(Debug)]
 pub struct MyProcess {
@@ -70,11 +166,229 @@ pub struct MyProcess {
     pg_dir_phy : PhysAddr,
     page_directory : VirtAddr,
     pub process_id : u16,
-    stack_size : u16,
-    started : bool,
-    terminated : bool,
+    stack_size : u64,
+    state : ProcessState,
     pub vm_pool : &'static mut VMPool,
-    next : *mut MyProcess
+    next : *mut MyProcess,
+    entry : u64, // process_start calls it with args
+    args : [u64; MAX_ARGS],
+    name : [u8; MAX_NAME_LEN],
+    priority : u8,
+    exit_code : u64,
+    parent_id : u16, // 0 when no process is left to wait for it
+    kernel_stack : u64, // top of the stack it uses in the kernel, 0 if it never leaves ring 0
+    user_stack : u64, // what rsp starts out as in ring 3
+    stack_top : u64, // of its stack region in the VMPool
+    thread_group : u16
+}
+
+/// Why a process is blocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    Wait(u16), // for the process with this id to exit
+    Sleep(u64) // for the timer to tick this many times since boot
+}
+
+/// Where a process is in its life. `set_state` only allows the transitions below.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    New, // created, never queued or run
+    Ready, // in the run queue
+    Running,
+    Blocked(BlockReason),
+    Zombie // exited, waiting for its exit code to be collected
+}
+
+impl ProcessState {
+    pub fn can_become(& self, next : ProcessState) -> bool {
+        use self::ProcessState::*;
+        match (*self, next) {
+            (New, Ready) | (New, Running) => true,
+            (Ready, Running) | (Ready, Zombie) => true,
+            (Running, Ready) | (Running, Blocked(_)) | (Running, Zombie) => true,
+            (Blocked(_), Ready) | (Blocked(_), Zombie) => true,
+            _ => false
+        }
+    }
+}
+
+/// Why a process or thread could not be created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    NoProcessId, // every process id is held
+    VM(VMError), // no room for its stacks
+    Elf(ElfError)
+}
+
+pub const MAX_ARGS : usize = 6; // the argument registers of the SysV ABI
+pub const MAX_NAME_LEN : usize = 16;
+
+/// Sets up a new process. Only the entry function is required:
+///
+/// `ProcessBuilder::with_arg(worker, &data as *const Data as u64).name("worker").spawn()`
+///
+/// The entry gets the arguments in rdi, rsi, rdx, rcx, r8 and r9 like any other SysV call,
+/// and the process ends when it returns.
+pub struct ProcessBuilder {
+    entry : u64,
+    args : [u64; MAX_ARGS],
+    stack_size : u64,
+    stack_limit : u64,
+    name : [u8; MAX_NAME_LEN],
+    priority : u8
+}
+
+#[allow(dead_code)]
+impl ProcessBuilder {
+    pub fn new(p_func_ptr : crate::machine::CFunc) -> ProcessBuilder {
+        ProcessBuilder::with_entry(p_func_ptr as u64)
+    }
+
+    fn with_entry(entry : u64) -> ProcessBuilder {
+        ProcessBuilder {
+            entry,
+            args : [0; MAX_ARGS],
+            stack_size : crate::machine::STACK_SIZE,
+            stack_limit : crate::machine::STACK_LIMIT,
+            name : [0; MAX_NAME_LEN],
+            priority : 0
+        }
+    }
+
+    pub fn with_arg(p_func_ptr : crate::machine::CFuncArg, arg : u64) -> ProcessBuilder {
+        let mut builder = ProcessBuilder::with_entry(p_func_ptr as u64);
+        builder.args[0] = arg;
+        builder
+    }
+
+    /// Up to MAX_ARGS arguments, the ones not given are 0.
+    pub fn with_args(p_func_ptr : crate::machine::CFuncArgs, args : &[u64]) -> ProcessBuilder {
+        ProcessBuilder::with_entry(p_func_ptr as u64).args(args)
+    }
+
+    /// A builder for `spawn_elf`, the entry point comes from the image.
+    pub fn program() -> ProcessBuilder {
+        ProcessBuilder::with_entry(0)
+    }
+
+    pub fn args(mut self, args : &[u64]) -> ProcessBuilder {
+        assert!(args.len() <= MAX_ARGS, "too many process arguments");
+        self.args = [0; MAX_ARGS];
+        self.args[..args.len()].copy_from_slice(args);
+        self
+    }
+
+    pub fn stack_size(mut self, stack_size : u64) -> ProcessBuilder {
+        self.stack_size = stack_size;
+        self
+    }
+
+    /// How far the stack may grow on demand, it never grows if this is at most the stack size.
+    pub fn stack_limit(mut self, stack_limit : u64) -> ProcessBuilder {
+        self.stack_limit = stack_limit;
+        self
+    }
+
+    /// Names the process, names longer than MAX_NAME_LEN bytes are cut short.
+    pub fn name(mut self, name : &str) -> ProcessBuilder {
+        let len = core::cmp::min(name.len(), MAX_NAME_LEN);
+        self.name = [0; MAX_NAME_LEN];
+        self.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        self
+    }
+
+    pub fn priority(mut self, priority : u8) -> ProcessBuilder {
+        self.priority = priority;
+        self
+    }
+
+    pub fn spawn(& self) -> Result<&'static mut MyProcess, SpawnError> {
+        crate::interrupts::disable_interrupts();
+
+        let my_process = match MyProcess::create() {
+            Ok(my_process) => my_process,
+            Err(error) => {
+                crate::interrupts::enable_interrupts();
+                return Err(error);
+            }
+        };
+        my_process.entry = self.entry;
+        my_process.args = self.args;
+        my_process.name = self.name;
+        my_process.priority = self.priority;
+        if let Err(error) = my_process.construct_stack(self.stack_size, self.stack_limit) {
+            my_process.release_address_space();
+            release_process(my_process);
+            crate::interrupts::enable_interrupts();
+            return Err(SpawnError::VM(error));
+        }
+
+        crate::interrupts::enable_interrupts();
+        Ok(my_process)
+    }
+
+    /// Loads the ELF64 executable `image` into a new process, which starts at the image's
+    /// entry point instead of the builder's function and runs in ring 3. Its segments go
+    /// where they are linked, which has to be inside the VMPool range.
+    pub fn spawn_elf(& self, image : &[u8]) -> Result<&'static mut MyProcess, SpawnError> {
+        crate::interrupts::disable_interrupts();
+
+        let my_process = match MyProcess::create() {
+            Ok(my_process) => my_process,
+            Err(error) => {
+                crate::interrupts::enable_interrupts();
+                return Err(error);
+            }
+        };
+        my_process.vm_pool.set_user_mode();
+        let (stack_size, stack_limit) = (self.stack_size, self.stack_limit);
+        let result = my_process.in_address_space(|process| {
+            process.entry = crate::elf::load(process.vm_pool, image).map_err(SpawnError::Elf)?.as_u64();
+            process.push_initial_frame(stack_size, stack_limit).map_err(SpawnError::VM)
+        });
+        if let Err(error) = result {
+            my_process.release_address_space();
+            release_process(my_process);
+            crate::interrupts::enable_interrupts();
+            return Err(error);
+        }
+        my_process.args = self.args;
+        my_process.name = self.name;
+        my_process.priority = self.priority;
+
+        crate::interrupts::enable_interrupts();
+        Ok(my_process)
+    }
+
+    /// Starts a thread of the current process running the builder's function and queues it.
+    /// Only processes that run in ring 0 have threads.
+    pub fn spawn_thread(& self) -> Result<&'static mut MyProcess, SpawnError> {
+        crate::interrupts::disable_interrupts();
+
+        let process = get_curr_process_table_mut();
+        assert!(!process.vm_pool.is_user_mode(), "ring 3 processes have no threads");
+        let thread = match MyProcess::create_thread(process) {
+            Ok(thread) => thread,
+            Err(error) => {
+                crate::interrupts::enable_interrupts();
+                return Err(error);
+            }
+        };
+        thread.entry = self.entry;
+        thread.args = self.args;
+        thread.name = self.name;
+        thread.priority = self.priority;
+        if let Err(error) = thread.construct_stack(self.stack_size, self.stack_limit) {
+            thread.release_address_space();
+            release_process(thread);
+            crate::interrupts::enable_interrupts();
+            return Err(SpawnError::VM(error));
+        }
+        crate::scheduler::resume(thread);
+
+        crate::interrupts::enable_interrupts();
+        Ok(thread)
+    }
 }
 
 fn get_page_table_from_addr(addr : u64) -> &'static mut PageTable {
@@ -83,6 +397,61 @@ fn get_page_table_from_addr(addr : u64) -> &'static mut PageTable {
     }
 }
 
+// page tables of any address space are reached through the direct map
+fn get_page_table_from_phy(addr : PhysAddr) -> &'static mut PageTable {
+    get_page_table_from_addr(crate::memory::phys_to_virt(addr).as_u64())
+}
+
+fn table_index(addr : VirtAddr, level : u8) -> usize {
+    ((addr.as_u64() >> (crate::machine::PAGE_OFFSET_BITS + 9 * (level - 1))) & 0o777) as usize
+}
+
+// lets ring 3 through the level 4 to level 2 entries on the way to `addr`, the level 1 entry
+// decides for itself. The tables the mapper creates are kernel only.
+fn allow_user_access(pg_dir_phy : PhysAddr, addr : VirtAddr) {
+    let mut table = get_page_table_from_phy(pg_dir_phy);
+    for level in (2..5).rev() {
+        let entry = &mut table[table_index(addr, level)];
+        if !entry.flags().contains(Flags::PRESENT) {
+            return;
+        }
+        entry.set_addr(entry.addr(), entry.flags() | Flags::USER_ACCESSIBLE);
+        table = get_page_table_from_phy(entry.addr());
+    }
+}
+
+// the level 1 entry for `addr` in the address space rooted at `pg_dir_phy`, if its tables exist
+fn get_p1_entry(pg_dir_phy : PhysAddr, addr : VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let mut table = get_page_table_from_phy(pg_dir_phy);
+    for level in (2..5).rev() {
+        let entry = &table[table_index(addr, level)];
+        if !entry.flags().contains(Flags::PRESENT) {
+            return None;
+        }
+        table = get_page_table_from_phy(entry.addr());
+    }
+    Some(&mut table[table_index(addr, 1)])
+}
+
+fn copy_frame(from : PhysAddr, to : PhysAddr) {
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            crate::memory::phys_to_virt(from).as_u64() as *const u8,
+            crate::memory::phys_to_virt(to).as_u64() as *mut u8,
+            crate::machine::PAGE_SIZE as usize);
+    }
+}
+
+// a frame handed to a process must not show what its last owner left in it
+fn zero_frame(frame : PhysFrame) {
+    unsafe {
+        core::ptr::write_bytes(
+            crate::memory::phys_to_virt(frame.start_address()).as_u64() as *mut u8,
+            0,
+            crate::machine::PAGE_SIZE as usize);
+    }
+}
+
 impl MyProcess {
     fn construct_page_table(& self) {
         let pg_table_addr = self.page_directory;
@@ -115,22 +484,53 @@ impl MyProcess {
         }
     }
 
-    fn construct_stack(&mut self, p_func_ptr : crate::machine::CFunc, _stack_size: u64) {
+    // runs `f` with this process' page table loaded, so what it touches in the VMPool is
+    // faulted into this process
+    fn in_address_space<R, F : FnOnce(&mut MyProcess) -> R>(&mut self, f : F) -> R {
         let old_cr3 = Cr3::read();
-        let old_pt = get_curr_process_table_mut();
+        let old_pt = unsafe { CURR_PROCESS_TABLE };
         unsafe {
             set_curr_process_table(self);
             Cr3::write(PhysFrame::containing_address(self.pg_dir_phy), old_cr3.1);
         }
 
-        let stack_frame = self.vm_pool.allocate(_stack_size as usize);
-        self.stack_size = _stack_size as u16;
-        self.esp = stack_frame.unwrap().as_u64() + _stack_size as u64;
+        let result = f(self);
+
+        unsafe {
+            Cr3::write(old_cr3.0, old_cr3.1);
+            CURR_PROCESS_TABLE = old_pt;
+        }
+        result
+    }
+
+    fn construct_stack(&mut self, _stack_size: u64, _stack_limit: u64) -> Result<(), VMError> {
+        self.in_address_space(|process| process.push_initial_frame(_stack_size, _stack_limit))
+    }
 
-        let pe = ((process_end as crate::machine::CFunc) as *const extern "C" fn()) as u64;
-        self.push(pe);
-        let pf = (p_func_ptr as *const extern "C" fn()) as u64;
-        self.push(pf);
+    // A ring 3 process gets a kernel stack as well, which is where process_start runs and
+    // where it enters the kernel on interrupts. Its VMPool stack is only used in ring 3.
+    fn push_initial_frame(&mut self, _stack_size: u64, _stack_limit: u64) -> Result<(), VMError> {
+        let stack_top = self.vm_pool.allocate_stack(_stack_size as usize, _stack_limit as usize)?;
+        self.stack_size = _stack_size;
+        self.esp = stack_top.as_u64();
+        self.stack_top = self.esp;
+
+        if self.vm_pool.is_user_mode() {
+            // entered like a call, returning exits
+            let exit_stub = crate::syscall::map_exit_stub(self.vm_pool)?;
+            self.push(exit_stub.as_u64());
+            self.user_stack = self.esp;
+
+            // in the VMPool like the other stacks, so an overflow hits its guard page
+            // instead of whatever lies next to it. The frame below maps its top page, the
+            // only one the cpu pushes to when it comes in from ring 3.
+            let kernel_stack = self.vm_pool.allocate_kernel_stack(crate::machine::KERNEL_STACK_SIZE as usize)?;
+            self.kernel_stack = kernel_stack.as_u64();
+            self.esp = self.kernel_stack;
+        }
+
+        // process_start never returns, this only keeps the stack aligned like after a call
+        self.push(0 as u64);
 
         let curr_esp = self.esp;
 
@@ -146,20 +546,48 @@ impl MyProcess {
         for _i in 0..15 {
             self.push(0 as u64);
         } // 16 general purpose registers - the stack register
+        Ok(())
+    }
 
-        unsafe {
-            Cr3::write(old_cr3.0, old_cr3.1);
-            set_curr_process_table(old_pt);
-        }
+    // a registered thread without an address space or anything to run yet
+    fn allocate() -> Result<&'static mut Self, SpawnError> {
+        let object = unsafe { PROCESS_CACHE.allocate() };
+        let fr_addr = object.unwrap().as_u64();
+        let thread : &mut MyProcess = unsafe {&mut *(fr_addr as *mut MyProcess)};
+
+        thread.next = 0x0 as *mut MyProcess;
+        thread.kernel_stack = 0;
+        thread.user_stack = 0;
+        thread.stack_top = 0;
+        thread.state = ProcessState::New;
+        thread.process_id = match register_process(thread) {
+            Some(pid) => pid,
+            None => {
+                unsafe { PROCESS_CACHE.free(VirtAddr::new(fr_addr)); }
+                return Err(SpawnError::NoProcessId);
+            }
+        };
+        thread.parent_id = unsafe {
+            if CURR_PROCESS_TABLE as u64 == 0x0 { 0 } else { (*CURR_PROCESS_TABLE).process_id }
+        };
+        Ok(thread)
     }
 
-    pub fn new(p_func_ptr : crate::machine::CFunc) -> &'static mut Self {
-        crate::interrupts::disable_interrupts();
+    // a thread sharing the address space of `process`
+    fn create_thread(process : &MyProcess) -> Result<&'static mut Self, SpawnError> {
+        let thread = MyProcess::allocate()?;
+        thread.pg_dir_phy = process.pg_dir_phy;
+        thread.page_directory = process.page_directory;
+        thread.vm_pool = unsafe { &mut *(process.vm_pool as *const VMPool as *mut VMPool) };
+        thread.thread_group = process.thread_group;
+        *ADDRESS_SPACE_USERS.lock().get_mut(&thread.pg_dir_phy.as_u64()).unwrap() += 1;
+        Ok(thread)
+    }
 
+    // a process with its own page table and an empty VMPool, but nothing to run yet
+    fn create() -> Result<&'static mut Self, SpawnError> {
         // init process instance
-        let frame = crate::memory::get_frame(true, false);
-        let fr_addr = frame.unwrap().start_address().as_u64();
-        let my_process : &mut MyProcess = unsafe {&mut *(fr_addr as *mut MyProcess)};
+        let my_process = MyProcess::allocate()?;
 
         my_process.pg_dir_phy = crate::memory::get_frame(true, true).unwrap().start_address();
         my_process.page_directory = crate::memory::transform_kernel_to_vir(my_process.pg_dir_phy);
@@ -167,20 +595,197 @@ impl MyProcess {
         my_process.construct_page_table();
 
         // VMPool Starts at 1GB and is of size 1GB
-        let vm_page = crate::memory::get_frame(true, false).unwrap().start_address().as_u64();
-        my_process.vm_pool = unsafe {&mut *(vm_page as *mut VMPool)};
-        my_process.vm_pool.new(
+        my_process.vm_pool = Box::leak(Box::new(VMPool::new(
             crate::machine::HEAP_START,
-            crate::machine::HEAP_SIZE);
+            crate::machine::HEAP_SIZE)));
 
+        my_process.thread_group = my_process.process_id;
+        ADDRESS_SPACE_USERS.lock().insert(my_process.pg_dir_phy.as_u64(), 1);
+        Ok(my_process)
+    }
+
+    pub fn new(p_func_ptr : crate::machine::CFunc) -> &'static mut Self {
+        ProcessBuilder::new(p_func_ptr).spawn().unwrap()
+    }
 
-        my_process.next = 0x0 as *mut MyProcess;
-        my_process.process_id = faa_next_proc_id();
+    pub fn name(& self) -> &str {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(MAX_NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
 
-        my_process.construct_stack(p_func_ptr, 8192);
+    pub fn priority(& self) -> u8 {
+        self.priority
+    }
 
-        crate::interrupts::enable_interrupts();
-        my_process
+    pub fn parent_id(& self) -> u16 {
+        self.parent_id
+    }
+
+    /// Id of the process the thread belongs to, its own id if it is a process.
+    pub fn thread_group(& self) -> u16 {
+        self.thread_group
+    }
+
+    pub fn threads(& self) -> impl Iterator<Item = &'static MyProcess> {
+        let group = self.thread_group;
+        processes().filter(move |process| process.thread_group == group)
+    }
+
+    pub fn children(& self) -> impl Iterator<Item = &'static MyProcess> {
+        let pid = self.process_id;
+        processes().filter(move |process| process.parent_id == pid)
+    }
+
+    /// Whether the process runs in ring 3.
+    pub fn is_user(& self) -> bool {
+        self.kernel_stack != 0
+    }
+
+    pub fn state(& self) -> ProcessState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state : ProcessState) {
+        if !self.state.can_become(state) {
+            panic!("process {} can't go from {:?} to {:?}", self.process_id, self.state, state);
+        }
+        self.state = state;
+    }
+
+    // Shares every page of the VMPool range with `child`. Writable pages become read-only
+    // copy-on-write pages in both address spaces, except for the pages in [private_start,
+    // private_end), which are copied right away. That is the stack we are running on:
+    // it can't be write protected without the next fault double faulting.
+    fn clone_address_space(& self, child : &mut MyProcess, private_start : VirtAddr, private_end : VirtAddr) {
+        let parent_p3 = get_page_table_from_phy(get_page_table_from_addr(self.page_directory.as_u64())[0].addr());
+        let child_p3 = get_page_table_from_phy(get_page_table_from_addr(child.page_directory.as_u64())[0].addr());
+        let first_p3 = table_index(VirtAddr::new(crate::machine::HEAP_START), 3);
+        let last_p3 = table_index(VirtAddr::new(crate::machine::HEAP_START + crate::machine::HEAP_SIZE - 1), 3);
+
+        for i3 in first_p3..(last_p3 + 1) {
+            if parent_p3[i3].is_unused() {
+                continue;
+            }
+            let parent_p2 = get_page_table_from_phy(parent_p3[i3].addr());
+            let child_p2_frame = crate::memory::get_frame(true, true).unwrap();
+            let child_p2 = get_page_table_from_phy(child_p2_frame.start_address());
+            child_p2.zero();
+            child_p3[i3].set_addr(child_p2_frame.start_address(), parent_p3[i3].flags());
+
+            for i2 in 0..512 {
+                if parent_p2[i2].is_unused() {
+                    continue;
+                }
+                let parent_p1 = get_page_table_from_phy(parent_p2[i2].addr());
+                let child_p1_frame = crate::memory::get_frame(true, true).unwrap();
+                let child_p1 = get_page_table_from_phy(child_p1_frame.start_address());
+                child_p1.zero();
+                child_p2[i2].set_addr(child_p1_frame.start_address(), parent_p2[i2].flags());
+
+                for i1 in 0..512 {
+                    if parent_p1[i1].is_unused() {
+                        continue;
+                    }
+                    let page_addr = VirtAddr::new(((i3 as u64) << 30) + ((i2 as u64) << 21) + ((i1 as u64) << 12));
+                    let frame_addr = parent_p1[i1].addr();
+                    let mut flags = parent_p1[i1].flags();
+
+                    if page_addr >= private_start && page_addr < private_end {
+                        let frame = crate::memory::get_frame(false, false).unwrap();
+                        copy_frame(frame_addr, frame.start_address());
+                        child_p1[i1].set_addr(frame.start_address(), flags);
+                        continue;
+                    }
+
+                    // shared regions stay shared, the rest is copied on the first write
+                    let shared = self.vm_pool.get_shared_page(page_addr).is_some();
+                    if flags.contains(Flags::WRITABLE) && !shared {
+                        flags.remove(Flags::WRITABLE);
+                        flags.insert(COPY_ON_WRITE);
+                        parent_p1[i1].set_addr(frame_addr, flags);
+                    }
+                    child_p1[i1].set_addr(frame_addr, flags);
+                    crate::memory::get_frame_ref(PhysFrame::containing_address(frame_addr));
+                }
+            }
+        }
+
+        tlb::flush_all();
+    }
+
+    // Drops the thread's use of its address space. The last thread using it frees all of it,
+    // kernel stack included, the others only hand back their stack.
+    fn release_address_space(&mut self) {
+        let key = self.pg_dir_phy.as_u64();
+        let last = {
+            let mut users = ADDRESS_SPACE_USERS.lock();
+            let count = users.get(&key).cloned().unwrap_or(1) - 1;
+            if count == 0 {
+                users.remove(&key);
+            } else {
+                users.insert(key, count);
+            }
+            count == 0
+        };
+        if last {
+            self.destroy_address_space();
+        } else {
+            self.in_address_space(|thread| thread.release_stack());
+        }
+    }
+
+    // the stack regions and the guard regions below them, however far they grew
+    fn release_stack(&mut self) {
+        for top in [self.stack_top, self.kernel_stack].iter().filter(|top| **top != 0) {
+            if let Some((start, _)) = self.vm_pool.get_region(VirtAddr::new(top - 1)) {
+                if let Some((guard, _)) = self.vm_pool.get_region(start - 1u64) {
+                    if self.vm_pool.is_guard(guard) {
+                        self.vm_pool.release(guard);
+                    }
+                }
+                self.vm_pool.release(start);
+            }
+        }
+        self.stack_top = 0;
+        self.kernel_stack = 0;
+    }
+
+    // Hands back everything the process owns but the struct itself: the frames mapped in its
+    // VMPool range, the page tables that map them, its own PML4 and PDPT and the VMPool.
+    // Mapped frames are only dropped once, so frames shared with other processes survive.
+    fn destroy_address_space(&mut self) {
+        let p4 = get_page_table_from_addr(self.page_directory.as_u64());
+        let p3_frame = PhysFrame::containing_address(p4[0].addr());
+        let p3 = get_page_table_from_phy(p3_frame.start_address());
+        let first_p3 = table_index(VirtAddr::new(crate::machine::HEAP_START), 3);
+        let last_p3 = table_index(VirtAddr::new(crate::machine::HEAP_START + crate::machine::HEAP_SIZE - 1), 3);
+
+        // p3[0] is the kernel's, only the VMPool range belongs to the process
+        for i3 in first_p3..(last_p3 + 1) {
+            if p3[i3].is_unused() {
+                continue;
+            }
+            let p2 = get_page_table_from_phy(p3[i3].addr());
+            for i2 in 0..512 {
+                if p2[i2].is_unused() {
+                    continue;
+                }
+                let p1 = get_page_table_from_phy(p2[i2].addr());
+                for i1 in 0..512 {
+                    if !p1[i1].is_unused() {
+                        crate::memory::free_frame(PhysFrame::containing_address(p1[i1].addr()));
+                    }
+                }
+                crate::memory::free_frame(PhysFrame::containing_address(p2[i2].addr()));
+            }
+            crate::memory::free_frame(PhysFrame::containing_address(p3[i3].addr()));
+        }
+        crate::memory::free_frame(p3_frame);
+        crate::memory::free_frame(PhysFrame::containing_address(self.pg_dir_phy));
+
+        unsafe {
+            drop(Box::from_raw(self.vm_pool as *mut VMPool));
+        }
     }
 
     pub fn load_page_table(&'static mut self) -> &'static mut Self {
@@ -191,7 +796,58 @@ impl MyProcess {
         self
     }
 
-    pub fn handle_fault(_addr : VirtAddr) -> bool {
+    // a write to a copy-on-write page: take over the frame if nobody else maps it, copy it otherwise
+    fn handle_cow_fault(addr : VirtAddr, prot : Protection) -> bool {
+        let entry = match get_p1_entry(Cr3::read().0.start_address(), addr) {
+            Some(entry) => entry,
+            None => return false
+        };
+        if !entry.flags().contains(Flags::PRESENT | COPY_ON_WRITE) {
+            return false;
+        }
+        let flags = prot.page_flags();
+
+        let old_frame = PhysFrame::containing_address(entry.addr());
+        if crate::memory::frame_ref_count(old_frame) == 1 {
+            entry.set_addr(old_frame.start_address(), flags);
+        } else {
+            let option = crate::memory::get_frame(false, false);
+            if option.is_none() {
+                return false;
+            }
+            let frame = option.unwrap();
+            copy_frame(old_frame.start_address(), frame.start_address());
+            entry.set_addr(frame.start_address(), flags);
+            crate::memory::put_frame_ref(old_frame);
+        }
+        tlb::flush(Page::<x86_64::structures::paging::Size4KiB>::containing_address(addr).start_address());
+        true
+    }
+
+    pub fn handle_fault(_addr : VirtAddr, error_code : PageFaultErrorCode) -> bool {
+        // running into the space below a stack grows it
+        let vm_pool = &mut get_curr_process_table_mut().vm_pool;
+        if vm_pool.is_guard(_addr) && !vm_pool.grow_stack(_addr) {
+            return false;
+        }
+
+        let prot = match get_curr_process_table().vm_pool.get_protection(_addr) {
+            Some(prot) => prot,
+            None => return false
+        };
+        if !prot.contains(Protection::READ)
+            || (error_code.contains(PageFaultErrorCode::USER_MODE) && !prot.contains(Protection::USER))
+            || (error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !prot.contains(Protection::WRITE))
+            || (error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && !prot.contains(Protection::EXECUTE)) {
+            return false;
+        }
+
+        // the page is there, the only violation the region allows is writing a copy-on-write page
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+                && MyProcess::handle_cow_fault(_addr, prot);
+        }
+
         unsafe {
 
             let vm_pool = &get_curr_process_table().vm_pool;
@@ -199,13 +855,31 @@ impl MyProcess {
                 let level_4_table_ptr = crate::machine::L4_PAGE_TABLE_VADDR as *mut PageTable;
                 let level_4_table = &mut *level_4_table_ptr;
                 let mut rptr = RecursivePageTable::new(level_4_table).unwrap();
-                let option = crate::memory::get_frame(false, false);
+                // a shared page maps the object's frame, with a reference of its own
+                let shared = vm_pool.get_shared_page(_addr);
+                let option = match shared {
+                    Some((handle, page)) => crate::shared_memory::frame(handle, page),
+                    None => crate::memory::get_frame(false, false)
+                        .map(|frame| { zero_frame(frame); frame })
+                };
                 if option.is_none() {
                     return false;
                 }
                 let frame = option.unwrap();
-                let result = rptr.map_to(Page::containing_address(_addr), frame, Flags::PRESENT | Flags::WRITABLE, crate::memory::get_frame_pool_mut(true));
-                result.is_ok()
+                let result = rptr.map_to(Page::containing_address(_addr), frame, prot.page_flags(), crate::memory::get_frame_pool_mut(true));
+                if result.is_err() {
+                    if shared.is_none() {
+                        crate::memory::free_frame(frame);
+                    }
+                    return false;
+                }
+                if shared.is_some() {
+                    crate::memory::get_frame_ref(frame);
+                }
+                if prot.contains(Protection::USER) {
+                    allow_user_access(Cr3::read().0.start_address(), _addr);
+                }
+                true
 //                true
             } else {
                 false
@@ -213,20 +887,43 @@ impl MyProcess {
         }
     }
 
+    // remaps an already mapped page with `prot`, a copy-on-write page stays read-only until written
+    pub fn protect_page(addr : VirtAddr, prot : Protection) {
+        let entry = match get_p1_entry(Cr3::read().0.start_address(), addr) {
+            Some(entry) => entry,
+            None => return
+        };
+        if entry.is_unused() {
+            return;
+        }
+        let mut flags = prot.page_flags();
+        if entry.flags().contains(COPY_ON_WRITE) {
+            flags.remove(Flags::WRITABLE);
+            flags.insert(COPY_ON_WRITE);
+        }
+        entry.set_addr(entry.addr(), flags);
+        tlb::flush(addr);
+    }
+
+    pub fn is_mapped(addr : VirtAddr) -> bool {
+        match get_p1_entry(Cr3::read().0.start_address(), addr) {
+            Some(entry) => !entry.is_unused(),
+            None => false
+        }
+    }
+
+    // a page protected without READ is not present, but it still holds its frame
     pub fn free_page(_addr : VirtAddr) {
-        unsafe {
-            let level_4_table_ptr = crate::machine::L4_PAGE_TABLE_VADDR as *mut PageTable;
-            let level_4_table = &mut *level_4_table_ptr;
-            let mut rptr = RecursivePageTable::new(level_4_table).unwrap();
-            let unmap_result = rptr.unmap(Page::containing_address(_addr));
-            if unmap_result.is_ok() {
-                let unmap = unmap_result.unwrap();
-                let frame = unmap.0;
-                let flush = unmap.1;
-                crate::memory::free_frame(frame);
-                flush.flush();
-            }
+        let entry = match get_p1_entry(Cr3::read().0.start_address(), _addr) {
+            Some(entry) => entry,
+            None => return
         };
+        if entry.is_unused() {
+            return;
+        }
+        crate::memory::free_frame(PhysFrame::containing_address(entry.addr()));
+        entry.set_unused();
+        tlb::flush(_addr);
     }
 
     pub fn get_vm_ref(&'static mut self) -> &mut VMPool {
@@ -269,18 +966,64 @@ pub unsafe fn print_pg_tables(table: u64) {
 //    rptr
 //}
 
+// Set while the page fault handler runs on its stack, there is only one of those.
+static IN_PAGE_FAULT : AtomicBool = AtomicBool::new(false);
+
+/// Called right before switching away from the current process. A process killed in the page
+/// fault handler switches away from the page fault stack and never runs again, so nothing it
+/// left there is needed and the next page fault may have the stack.
+pub fn leave_fault_stack() {
+    IN_PAGE_FAULT.store(false, Ordering::SeqCst);
+}
+
 pub extern "x86-interrupt" fn page_fault_handler(
     stack_frame: &mut ExceptionStackFrame,
-    _error_code: PageFaultErrorCode,
+    error_code: PageFaultErrorCode,
 ) {
     let addr = Cr2::read();
 
-    if !MyProcess::handle_fault(addr) {
+    // every page fault starts at the top of the same stack, a second one would overwrite this one
+    if IN_PAGE_FAULT.swap(true, Ordering::SeqCst) {
+        panic!("page fault at {:?} while handling a page fault", addr);
+    }
+
+    if !MyProcess::handle_fault(addr, error_code) {
+        // the kernel copying from or to user memory gets an error back instead
+        if let Some(fixup) = crate::user_memory::fixup_address(stack_frame.instruction_pointer) {
+            unsafe {
+                core::ptr::write_volatile(&mut stack_frame.instruction_pointer, fixup);
+            }
+            IN_PAGE_FAULT.store(false, Ordering::SeqCst);
+            return;
+        }
+        // This runs on its own stack, so a process that ran out of its own can still be ended.
+        // kill leaves that stack for good when it switches away, see leave_fault_stack.
+        if get_curr_process_table().vm_pool.is_guard(addr) {
+            println!("EXCEPTION: STACK OVERFLOW");
+            kill(format_args!("ran past its stack at {:?}", addr));
+        }
+        // a fault in ring 3 only takes down the process that caused it
+        if from_user_mode(stack_frame) {
+            kill(format_args!("page fault at {:?}", addr));
+        }
         println!("EXCEPTION: PAGE FAULT");
         println!("Accessed Address: {:?}", addr);
         println!("{:#?}", stack_frame);
         panic!();
     }
+    IN_PAGE_FAULT.store(false, Ordering::SeqCst);
+}
+
+/// Whether the interrupt or exception came in while ring 3 code ran.
+pub fn from_user_mode(stack_frame : & ExceptionStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Ends the current process with KILLED_EXIT_CODE for what it did wrong.
+pub fn kill(cause : core::fmt::Arguments) -> ! {
+    let process = get_curr_process_table();
+    println!("Process {} killed: {}", process.process_id, cause);
+    exit(KILLED_EXIT_CODE);
 }
 
 macro_rules! save_all_registers {
@@ -362,23 +1105,331 @@ pub extern "C" fn process_switch_to() {
         : "r"(NEXT_PROCESS)
         ::"volatile", "intel");
 
+        // threads of one process share the page table, reloading it would only flush the TLB
         asm!("mov rax, [rbx+8]
               mov rsp, [rbx]
-              mov cr3, rax"
+              mov rcx, cr3
+              cmp rcx, rax
+              je same_address_space_${:uid}
+              mov cr3, rax
+              same_address_space_${:uid}:"
+        ::::"volatile", "intel");
+
+        restore_all_registers!();
+
+        asm!("iretq" ::::"volatile", "intel");
+
+    }
+}
+
+// Builds the child for `fork`. `esp` points at the registers `fork` saved, which is exactly
+// what process_switch_to restores, so the child resumes by returning from `fork` as well.
+#[no_mangle]
+extern "C" fn fork_current(esp : u64) -> u64 {
+    crate::interrupts::disable_interrupts();
+    let parent = get_curr_process_table_mut();
+
+    // the saved rax is the child's return value, it has to be in place before the stack is copied
+    unsafe {
+        *((esp + 14 * 8) as *mut u64) = 0;
+    }
+
+    let child = match MyProcess::create() {
+        Ok(child) => child,
+        Err(_) => {
+            crate::interrupts::enable_interrupts();
+            return FORK_FAILED;
+        }
+    };
+    child.vm_pool.copy_from(parent.vm_pool);
+    child.stack_size = parent.stack_size;
+    child.stack_top = parent.stack_top;
+    child.entry = parent.entry;
+    child.args = parent.args;
+    child.name = parent.name;
+    child.priority = parent.priority;
+    child.esp = esp;
+
+    let (stack_start, stack_end) = parent.vm_pool.get_region(VirtAddr::new(esp))
+        .unwrap_or((VirtAddr::new(0), VirtAddr::new(0)));
+    parent.clone_address_space(child, stack_start, stack_end);
+
+    crate::scheduler::resume(child);
+    crate::interrupts::enable_interrupts();
+    child.process_id as u64
+}
+
+/// Duplicates the current process: the child gets a copy-on-write copy of the parent's VMPool
+/// and is queued on the scheduler. Returns the child's process id in the parent and 0 in the
+/// child, or FORK_FAILED in the parent alone.
+#[naked]
+pub extern "C" fn fork() -> u64 {
+    unsafe {
+        // the same frame process_switch_to pushes, with the selectors fork runs with rather
+        // than fixed ones, so it returns to the kernel whatever the GDT looks like
+        asm!("
+              mov [rsp-40], rax
+              mov rax, [rsp+0]
+              mov [rsp-32], rax
+              add rsp, 8
+              mov rax, ss
+              push rax
+              mov rax, rsp
+              add rax, 8
+              push rax
+              pushfq
+              mov rax, cs
+              push rax
+              sub rsp, 8
+              mov rax, [rsp-8]
+              "
+        ::::"volatile", "intel");
+
+        save_all_registers!();
+
+        // the parent returns the child's id in rax
+        asm!("mov rdi, rsp
+              call fork_current
+              mov [rsp+112], rax"
         ::::"volatile", "intel");
 
         restore_all_registers!();
 
         asm!("iretq" ::::"volatile", "intel");
+    }
+}
+
+/// Frees the address space of every process that ended since the last call. It must not run
+/// on the address space of one of them, which is why a process can't do this for itself in
+/// `exit`. What is left of them stays a zombie until `wait` collects it, unless it has no
+/// parent to do that. Its children lose their parent.
+pub fn reap_terminated() {
+    crate::interrupts::disable_interrupts();
+    unsafe {
+        while TERMINATED_PROCESSES as u64 != 0x0 {
+            let process = &mut *TERMINATED_PROCESSES;
+            TERMINATED_PROCESSES = process.next;
+            process.release_address_space();
+
+            let orphans : Vec<*mut MyProcess> = process.children()
+                .map(|child| child as *const MyProcess as *mut MyProcess)
+                .collect();
+            for orphan in orphans {
+                (*orphan).parent_id = 0;
+                // a terminated child still in the list is released when its turn comes
+                if (*orphan).state == ProcessState::Zombie && !is_terminated(&*orphan) {
+                    release_process(&mut *orphan);
+                }
+            }
+            if process.parent_id == 0 {
+                release_process(process);
+            }
+        }
+    }
+    crate::interrupts::enable_interrupts();
+}
 
+fn is_terminated(process : & MyProcess) -> bool {
+    unsafe {
+        let mut current = TERMINATED_PROCESSES;
+        while current as u64 != 0x0 {
+            if current as *const MyProcess == process as *const MyProcess {
+                return true;
+            }
+            current = (*current).next;
+        }
+    }
+    false
+}
+
+// releases the reaped zombie with `pid` and returns its exit code
+fn collect_zombie(pid : u16) -> Option<u64> {
+    let process = get_process(pid)?;
+    if process.state != ProcessState::Zombie || is_terminated(process) {
+        return None;
+    }
+    let exit_code = process.exit_code;
+    release_process(process);
+    Some(exit_code)
+}
+
+/// Ends the current process with `code`, all of its threads included, and runs the next
+/// ready one. The address space is freed by whoever runs next, the exit code stays around
+/// until `wait` picks it up.
+pub fn exit(code : u64) -> ! {
+    crate::interrupts::disable_interrupts();
+    let current = get_curr_process_table();
+    let others : Vec<*mut MyProcess> = current.threads()
+        .filter(|thread| thread.process_id != current.process_id && thread.state != ProcessState::Zombie)
+        .map(|thread| thread as *const MyProcess as *mut MyProcess)
+        .collect();
+    // they are stopped wherever they are, in the run queue or blocked in wait
+    for thread in others {
+        unsafe { end_thread(&mut *thread, code); }
+    }
+    thread_exit(code);
+}
+
+/// Ends the current thread with `code`, the rest of its process goes on. Like `exit` for
+/// a process, the exit code stays around until `thread_join` picks it up.
+pub fn thread_exit(code : u64) -> ! {
+    crate::interrupts::disable_interrupts();
+    let process = get_curr_process_table_mut();
+    process.set_state(ProcessState::Zombie);
+    process.exit_code = code;
+    crate::scheduler::terminate(process);
+    wake_waiters(process.process_id);
+    unsafe {
+        process.next = TERMINATED_PROCESSES;
+        TERMINATED_PROCESSES = process;
+    }
+    crate::scheduler::block();
+    unreachable!();
+}
+
+// ends a thread that isn't running, as if it called thread_exit
+fn end_thread(thread : &mut MyProcess, code : u64) {
+    crate::scheduler::terminate(thread);
+    unsafe {
+        let mut link : *mut *mut MyProcess = &mut WAITING_PROCESSES;
+        while *link as u64 != 0x0 {
+            if *link == thread as *mut MyProcess {
+                *link = thread.next;
+                break;
+            }
+            link = &mut (**link).next;
+        }
+    }
+    thread.set_state(ProcessState::Zombie);
+    thread.exit_code = code;
+    wake_waiters(thread.process_id);
+    unsafe {
+        thread.next = TERMINATED_PROCESSES;
+        TERMINATED_PROCESSES = thread;
+    }
+}
+
+// readies the processes blocked in `wait` for `pid`
+fn wake_waiters(pid : u16) {
+    unsafe {
+        let mut link : *mut *mut MyProcess = &mut WAITING_PROCESSES;
+        while *link as u64 != 0x0 {
+            let process = &mut **link;
+            if process.state == ProcessState::Blocked(BlockReason::Wait(pid)) {
+                *link = process.next;
+                process.set_next(None);
+                crate::scheduler::resume(process);
+            } else {
+                link = &mut process.next;
+            }
+        }
+    }
+}
+
+/// Blocks until the child `pid` has exited and returns its exit code. Returns None if the
+/// caller has no such child, or if it lost its parent and was released on exit.
+pub fn wait(pid : u16) -> Option<u64> {
+    let current = get_curr_process_table();
+    match get_process(pid) {
+        Some(child) if child.parent_id == current.process_id => wait_for(pid),
+        _ => None
+    }
+}
+
+// blocks until `pid` exits and collects it, whoever may do that is up to the caller
+fn wait_for(pid : u16) -> Option<u64> {
+    loop {
+        reap_terminated();
+        if let Some(exit_code) = collect_zombie(pid) {
+            return Some(exit_code);
+        }
+        if get_process(pid).is_none() || pid == get_curr_process_table().process_id {
+            return None;
+        }
+
+        crate::interrupts::disable_interrupts();
+        let process = get_curr_process_table_mut();
+        process.set_state(ProcessState::Blocked(BlockReason::Wait(pid)));
+        unsafe {
+            process.next = WAITING_PROCESSES;
+            WAITING_PROCESSES = process;
+        }
+        crate::scheduler::block();
+    }
+}
+
+/// Starts `func(arg)` in a new thread of the current process and returns the thread's id.
+pub fn thread_create(func : crate::machine::CFuncArg, arg : u64) -> Result<u16, SpawnError> {
+    ProcessBuilder::with_arg(func, arg).spawn_thread().map(|thread| thread.process_id)
+}
+
+/// Blocks until the thread `tid` of the current process has exited and returns its exit
+/// code. Returns None if the process has no such thread, or if it is the caller.
+pub fn thread_join(tid : u16) -> Option<u64> {
+    let current = get_curr_process_table();
+    match get_process(tid) {
+        Some(thread) if thread.thread_group == current.thread_group && tid != current.process_id => wait_for(tid),
+        _ => None
     }
 }
 
 extern "C" fn process_start() {
-    get_curr_process_table_mut().started = true;
+    let process = get_curr_process_table_mut();
+    // a process switched to directly never went through the scheduler
+    if process.state == ProcessState::New {
+        process.set_state(ProcessState::Running);
+    }
+    reap_terminated();
     crate::interrupts::enable_interrupts();
+
+    if process.is_user() {
+        enter_user_mode(process.entry, process.user_stack, process.args);
+    }
+
+    // an entry that takes fewer arguments just ignores the other registers
+    let entry : crate::machine::CFuncArgs = unsafe { core::mem::transmute(process.entry) };
+    let args = process.args;
+    entry(args[0], args[1], args[2], args[3], args[4], args[5]);
+
+    process_end();
 }
 
+// returning from the entry function is exiting with 0, from a thread's only ends the thread
 extern "C" fn process_end() {
-    crate::hlt_loop();
-}
\ No newline at end of file
+    let process = get_curr_process_table();
+    if process.process_id != process.thread_group {
+        thread_exit(0);
+    }
+    exit(0);
+}
+
+// Drops to ring 3 at `entry` on `stack`, with the arguments where a SysV call has them.
+// Interrupts come back to the kernel stack in the TSS, which is this one. Every other
+// register is cleared, whatever the kernel left in them is none of the program's business.
+// It never comes back, so the registers it clears don't have to be declared.
+fn enter_user_mode(entry : u64, stack : u64, args : [u64; MAX_ARGS]) -> ! {
+    let cs = crate::gdt::get_user_cs();
+    let ss = crate::gdt::get_user_ss();
+    unsafe {
+        asm!("push $0
+              push $1
+              push $2
+              push $3
+              push $4
+              xor eax, eax
+              xor ebx, ebx
+              xor ebp, ebp
+              xor r10d, r10d
+              xor r11d, r11d
+              xor r12d, r12d
+              xor r13d, r13d
+              xor r14d, r14d
+              xor r15d, r15d
+              iretq"
+        :: "r"(ss), "r"(stack), "r"(USER_RFLAGS), "r"(cs), "r"(entry),
+           "{rdi}"(args[0]), "{rsi}"(args[1]), "{rdx}"(args[2]),
+           "{rcx}"(args[3]), "{r8}"(args[4]), "{r9}"(args[5])
+        :: "volatile", "intel");
+    }
+    unreachable!();
+}
@@ -1,12 +1,20 @@
 use crate::{
-    process_table::MyProcess,
+    process_table::{MyProcess, ProcessState, BlockReason},
     serial_println
 };
 use spin::Mutex;
 
 struct MyScheduler {
     head : *mut MyProcess,
-    tail : *mut MyProcess
+    tail : *mut MyProcess,
+    sleeping : *mut MyProcess // blocked in sleep, in no particular order
+}
+
+fn next_of(proc : &mut MyProcess) -> *mut MyProcess {
+    match proc.get_next() {
+        Some(val) => val as *mut MyProcess,
+        None => 0x0 as *mut MyProcess
+    }
 }
 
 #[allow(dead_code)]
@@ -44,6 +52,7 @@ impl MyScheduler {
     }
 
     pub fn _yield(&mut self) {
+        self.wake_sleepers();
         let x = self.pop();
         let option = x.0;
         let self_ref = x.1;
@@ -54,10 +63,41 @@ impl MyScheduler {
         let resume_pt = crate::process_table::get_curr_process_table_mut();
 //        serial_println!("{} -> {}", resume_pt.process_id, yield_pt.process_id);
         resume_pt.set_next(None);
+        resume_pt.set_state(ProcessState::Ready);
         self_ref.resume(resume_pt);
 
+        yield_pt.set_state(ProcessState::Running);
         crate::process_table::set_next_process(yield_pt);
         crate::process_table::process_switch_to();
+
+        // whoever ended while we were away can be cleaned up now that their tables aren't loaded
+        crate::process_table::reap_terminated();
+    }
+
+    // switches to the next process without putting the current one back in the queue,
+    // it is up to whoever makes it ready again to resume it
+    pub fn block(&mut self) {
+        let next_pt = loop {
+            self.wake_sleepers();
+            if let Some(next_pt) = self.pop().0 {
+                break next_pt as *mut MyProcess;
+            }
+            // nothing to run until an interrupt readies someone, which it can't with them off
+            crate::interrupts::enable_interrupts();
+            x86_64::instructions::hlt();
+            crate::interrupts::disable_interrupts();
+        };
+        let next_pt = unsafe { &mut *next_pt };
+
+        next_pt.set_state(ProcessState::Running);
+        // readied again while this was idling, it just goes on
+        if next_pt as *mut MyProcess != crate::process_table::get_curr_process_table_mut() as *mut MyProcess {
+            crate::process_table::leave_fault_stack();
+            crate::process_table::set_next_process(next_pt);
+            crate::process_table::process_switch_to();
+        }
+
+        crate::process_table::reap_terminated();
     }
 
     pub fn resume(&mut self, proc : &mut MyProcess) {
@@ -71,14 +111,84 @@ impl MyScheduler {
         }
     }
 
-    pub fn terminate(&mut self, _proc : &mut MyProcess) {
+    // the current process, blocked until the tick count reaches the one it sleeps for
+    pub fn sleep(&mut self, proc : &mut MyProcess) {
+        proc.set_next(unsafe { self.sleeping.as_mut() });
+        self.sleeping = proc;
+    }
 
+    // takes the sleeper after `prev` out of the sleeping list, `next` is the one after it
+    fn unlink_sleeper(&mut self, prev : *mut MyProcess, next : *mut MyProcess) {
+        if prev == 0x0 as *mut MyProcess {
+            self.sleeping = next;
+        } else {
+            unsafe { (*prev).set_next(next.as_mut()); }
+        }
+    }
+
+    // queues the sleepers whose tick has come, everyone who schedules checks for them
+    fn wake_sleepers(&mut self) {
+        let now = crate::interrupts::ticks();
+        let mut prev = 0x0 as *mut MyProcess;
+        let mut current = self.sleeping;
+        while current != 0x0 as *mut MyProcess {
+            let proc = unsafe { &mut *current };
+            let next = next_of(proc);
+            match proc.state() {
+                ProcessState::Blocked(BlockReason::Sleep(until)) if until <= now => {
+                    self.unlink_sleeper(prev, next);
+                    proc.set_next(None);
+                    proc.set_state(ProcessState::Ready);
+                    self.resume(proc);
+                },
+                _ => prev = current
+            }
+            current = next;
+        }
+    }
+
+    // takes a process out of the run queue or the sleeping list, if it is in one
+    pub fn terminate(&mut self, proc : &mut MyProcess) {
+        let target = proc as *mut MyProcess;
+        let mut prev = 0x0 as *mut MyProcess;
+        let mut current = self.head;
+        while current != 0x0 as *mut MyProcess {
+            let next = next_of(unsafe { &mut *current });
+            if current == target {
+                if prev == 0x0 as *mut MyProcess {
+                    self.head = next;
+                } else {
+                    unsafe { (*prev).set_next(if next == 0x0 as *mut MyProcess { None } else { Some(&mut *next) }); }
+                }
+                if self.tail == target {
+                    self.tail = prev;
+                }
+                proc.set_next(None);
+                return;
+            }
+            prev = current;
+            current = next;
+        }
+
+        let mut prev = 0x0 as *mut MyProcess;
+        let mut current = self.sleeping;
+        while current != 0x0 as *mut MyProcess {
+            let next = next_of(unsafe { &mut *current });
+            if current == target {
+                self.unlink_sleeper(prev, next);
+                proc.set_next(None);
+                return;
+            }
+            prev = current;
+            current = next;
+        }
     }
 }
 
 static mut SYSTEM_SCHEDULER : MyScheduler = MyScheduler {
     head : 0x0 as *mut MyProcess,
-    tail : 0x0 as *mut MyProcess
+    tail : 0x0 as *mut MyProcess,
+    sleeping : 0x0 as *mut MyProcess
 };
 
 static mut SCHEDULER_MUTEX : Mutex<bool> = Mutex::new(true);
@@ -90,7 +200,43 @@ pub fn _yield() {
     }
 }
 
+/// Runs the next ready process, the current one has to be blocked or a zombie already.
+/// With none ready it idles with interrupts on until one is.
+pub fn block() {
+    unsafe {
+        SCHEDULER_MUTEX.lock();
+        SYSTEM_SCHEDULER.block();
+    }
+}
+
+/// Blocks the current process until the timer ticked `ticks` more times, the others run
+/// in the meantime.
+pub fn sleep(ticks : u64) {
+    crate::interrupts::disable_interrupts();
+    let until = crate::interrupts::ticks().saturating_add(ticks);
+    let proc = crate::process_table::get_curr_process_table_mut();
+    proc.set_state(ProcessState::Blocked(BlockReason::Sleep(until)));
+    unsafe {
+        SCHEDULER_MUTEX.lock();
+        SYSTEM_SCHEDULER.sleep(proc);
+        SYSTEM_SCHEDULER.block();
+    }
+}
+
+pub fn terminate(proc : &mut MyProcess) {
+    unsafe {
+        SCHEDULER_MUTEX.lock();
+        SYSTEM_SCHEDULER.terminate(proc);
+    }
+}
+
+/// Queues a new or blocked process. Anything else is a bug in the caller, a running
+/// process is already on the cpu and a ready one is already in the queue.
 pub fn resume(proc : &mut MyProcess) {
+    match proc.state() {
+        ProcessState::New | ProcessState::Blocked(_) => proc.set_state(ProcessState::Ready),
+        state => panic!("can't resume process {} in state {:?}", proc.process_id, state)
+    }
     unsafe {
         SCHEDULER_MUTEX.lock();
         SYSTEM_SCHEDULER.resume(proc);
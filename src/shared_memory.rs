@@ -0,0 +1,100 @@
+use x86_64::structures::paging::PhysFrame;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Names a shared memory object. Each handle returned by `create` and each region mapping
+/// the object holds a reference, the frames are released once the last one is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SharedHandle(u64);
+
+struct SharedObject {
+    frames : Vec<Option<PhysFrame>>, // allocated the first time a page is touched
+    references : u64
+}
+
+struct SharedObjects {
+    next_handle : u64,
+    objects : BTreeMap<SharedHandle, SharedObject>
+}
+
+lazy_static! {
+    static ref SHARED_OBJECTS : Mutex<SharedObjects> = Mutex::new(SharedObjects {
+        next_handle : 1,
+        objects : BTreeMap::new()
+    });
+}
+
+/// Creates a shared memory object of `size` bytes, rounded up to whole pages. It can't be
+/// larger than a VMPool, nothing could map it.
+pub fn create(size : u64) -> Option<SharedHandle> {
+    let pages = size.checked_add(crate::machine::PAGE_SIZE - 1)? >> crate::machine::PAGE_OFFSET_BITS;
+    if pages == 0 || pages > crate::machine::HEAP_SIZE >> crate::machine::PAGE_OFFSET_BITS {
+        return None;
+    }
+    // the frame table comes from the kernel heap, which may not have room for it
+    let mut frames = Vec::new();
+    frames.try_reserve_exact(pages as usize).ok()?;
+    frames.resize(pages as usize, None);
+
+    let mut shared_objects = SHARED_OBJECTS.lock();
+    let handle = SharedHandle(shared_objects.next_handle);
+    shared_objects.next_handle += 1;
+    shared_objects.objects.insert(handle, SharedObject { frames, references : 1 });
+    Some(handle)
+}
+
+/// Size of the object in pages.
+pub fn page_count(handle : SharedHandle) -> Option<u64> {
+    SHARED_OBJECTS.lock().objects.get(&handle).map(|object| object.frames.len() as u64)
+}
+
+pub fn get(handle : SharedHandle) {
+    if let Some(object) = SHARED_OBJECTS.lock().objects.get_mut(&handle) {
+        object.references += 1;
+    }
+}
+
+/// Drops a reference, the last one frees the object's frames. Frames that are still
+/// mapped somewhere stay allocated until they are unmapped, as their counts say.
+pub fn put(handle : SharedHandle) {
+    let mut shared_objects = SHARED_OBJECTS.lock();
+    let last = match shared_objects.objects.get_mut(&handle) {
+        Some(object) => {
+            object.references -= 1;
+            object.references == 0
+        },
+        None => false
+    };
+    if last {
+        let object = shared_objects.objects.remove(&handle).unwrap();
+        for frame in object.frames.iter().filter_map(|frame| *frame) {
+            crate::memory::free_frame(frame);
+        }
+    }
+}
+
+/// Drops the reference `create` returned.
+pub fn close(handle : SharedHandle) {
+    put(handle);
+}
+
+/// The frame backing page `index` of the object, allocated and zeroed on first use.
+/// The object keeps its own reference, a caller mapping the frame has to take another one.
+pub fn frame(handle : SharedHandle, index : u64) -> Option<PhysFrame> {
+    let mut shared_objects = SHARED_OBJECTS.lock();
+    let object = shared_objects.objects.get_mut(&handle)?;
+    let slot = object.frames.get_mut(index as usize)?;
+    if slot.is_none() {
+        let frame = crate::memory::get_frame(false, false)?;
+        unsafe {
+            core::ptr::write_bytes(
+                crate::memory::phys_to_virt(frame.start_address()).as_u64() as *mut u8,
+                0,
+                crate::machine::PAGE_SIZE as usize);
+        }
+        *slot = Some(frame);
+    }
+    *slot
+}
@@ -0,0 +1,152 @@
+use x86_64::{
+    structures::paging::PhysFrame,
+    VirtAddr
+};
+use core::mem::size_of;
+
+const OBJECT_ALIGN : u64 = 16;
+const SLAB_HEADER_SIZE : u64 = (size_of::<Slab>() as u64 + OBJECT_ALIGN - 1) & !(OBJECT_ALIGN - 1);
+
+// sits at the start of every slab frame, the objects follow it
+#[repr(C)]
+struct Slab {
+    next : *mut Slab,
+    prev : *mut Slab,
+    free_list : *mut FreeObject,
+    in_use : u64
+}
+
+struct FreeObject {
+    next : *mut FreeObject
+}
+
+/// Cache of same-sized kernel objects packed into frames from the system frame pool.
+/// Slabs with free objects are kept on the partial list, the others on the full list,
+/// and a slab's frame goes back to the pool as soon as its last object is freed.
+pub struct SlabCache {
+    object_size : u64,
+    partial : *mut Slab,
+    full : *mut Slab,
+    slabs : u64,
+    objects_in_use : u64
+}
+
+fn push_slab(list : &mut *mut Slab, slab : *mut Slab) {
+    unsafe {
+        (*slab).prev = 0x0 as *mut Slab;
+        (*slab).next = *list;
+        if !(*list).is_null() {
+            (**list).prev = slab;
+        }
+    }
+    *list = slab;
+}
+
+fn unlink_slab(list : &mut *mut Slab, slab : *mut Slab) {
+    unsafe {
+        if (*slab).prev.is_null() {
+            *list = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl SlabCache {
+    pub const fn new(object_size : u64) -> SlabCache {
+        SlabCache {
+            object_size : (object_size + OBJECT_ALIGN - 1) & !(OBJECT_ALIGN - 1),
+            partial : 0x0 as *mut Slab,
+            full : 0x0 as *mut Slab,
+            slabs : 0,
+            objects_in_use : 0
+        }
+    }
+
+    pub fn objects_per_slab(& self) -> u64 {
+        (crate::machine::PAGE_SIZE - SLAB_HEADER_SIZE) / self.object_size
+    }
+
+    pub fn slab_count(& self) -> u64 {
+        self.slabs
+    }
+
+    pub fn objects_in_use(& self) -> u64 {
+        self.objects_in_use
+    }
+
+    fn grow(&mut self) -> bool {
+        let frame = crate::memory::get_frame(true, false);
+        if frame.is_none() {
+            return false;
+        }
+        let slab_addr = frame.unwrap().start_address().as_u64();
+        let slab = slab_addr as *mut Slab;
+        unsafe {
+            (*slab).free_list = 0x0 as *mut FreeObject;
+            (*slab).in_use = 0;
+            for i in (0..self.objects_per_slab()).rev() {
+                let object = (slab_addr + SLAB_HEADER_SIZE + i * self.object_size) as *mut FreeObject;
+                (*object).next = (*slab).free_list;
+                (*slab).free_list = object;
+            }
+        }
+        push_slab(&mut self.partial, slab);
+        self.slabs += 1;
+        true
+    }
+
+    /// Hands out a zeroed object, growing the cache by one slab if all slabs are full.
+    pub fn allocate(&mut self) -> Option<VirtAddr> {
+        if self.partial.is_null() && !self.grow() {
+            return None;
+        }
+
+        let slab = self.partial;
+        let object = unsafe {
+            let object = (*slab).free_list;
+            (*slab).free_list = (*object).next;
+            (*slab).in_use += 1;
+            object
+        };
+        self.objects_in_use += 1;
+
+        if unsafe { (*slab).free_list.is_null() } {
+            unlink_slab(&mut self.partial, slab);
+            push_slab(&mut self.full, slab);
+        }
+
+        unsafe {
+            core::ptr::write_bytes(object as *mut u8, 0, self.object_size as usize);
+        }
+        Some(VirtAddr::new(object as u64))
+    }
+
+    pub fn free(&mut self, addr : VirtAddr) {
+        let slab = (addr.as_u64() & !(crate::machine::PAGE_SIZE - 1)) as *mut Slab;
+        let object = addr.as_u64() as *mut FreeObject;
+        let was_full = unsafe { (*slab).free_list.is_null() };
+        unsafe {
+            (*object).next = (*slab).free_list;
+            (*slab).free_list = object;
+            (*slab).in_use -= 1;
+        }
+        self.objects_in_use -= 1;
+
+        if was_full {
+            unlink_slab(&mut self.full, slab);
+            push_slab(&mut self.partial, slab);
+        }
+
+        if unsafe { (*slab).in_use } == 0 {
+            unlink_slab(&mut self.partial, slab);
+            self.slabs -= 1;
+            let frame_addr = crate::memory::transform_kernel_to_phy(VirtAddr::new(slab as u64));
+            crate::memory::free_frame(PhysFrame::containing_address(frame_addr));
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use x86_64::VirtAddr;
+use crate::{
+    print,
+    process_table,
+    user_memory,
+    vm_pool::{VMPool, VMError, Protection}
+};
+use alloc::vec::Vec;
+
+// a system call is `int 0x80` with its number in rax and its arguments in rdi, rsi and rdx,
+// rax holds the result afterwards and every other register is preserved
+pub const SYS_YIELD : u64 = 0;
+pub const SYS_EXIT : u64 = 1;
+pub const SYS_WRITE : u64 = 2;
+pub const SYS_ALLOCATE : u64 = 3;
+pub const SYS_RELEASE : u64 = 4;
+pub const SYS_GETPID : u64 = 5;
+pub const SYS_SLEEP : u64 = 6;
+
+const MAX_WRITE : u64 = 0o10_000; // 4KB at a time
+
+/// Why a system call failed. It comes back as the negated value in rax.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    NoSuchCall = 1,
+    InvalidArgument = 2,
+    OutOfMemory = 3,
+    BadAddress = 4 // memory the caller doesn't have, or can't be used like that
+}
+
+impl SyscallError {
+    pub fn code(& self) -> u64 {
+        (-(*self as i64)) as u64
+    }
+
+    /// The error a system call returned, if it did fail.
+    pub fn from_result(result : u64) -> Option<SyscallError> {
+        match (result as i64).wrapping_neg() {
+            1 => Some(SyscallError::NoSuchCall),
+            2 => Some(SyscallError::InvalidArgument),
+            3 => Some(SyscallError::OutOfMemory),
+            4 => Some(SyscallError::BadAddress),
+            _ => None
+        }
+    }
+}
+
+impl From<VMError> for SyscallError {
+    fn from(error : VMError) -> SyscallError {
+        match error {
+            VMError::OutOfSpace => SyscallError::OutOfMemory,
+            _ => SyscallError::InvalidArgument
+        }
+    }
+}
+
+type Syscall = fn(&[u64; 3]) -> Result<u64, SyscallError>;
+
+// indexed by the call number
+static SYSCALLS : [Syscall; 7] = [
+    sys_yield,
+    sys_exit,
+    sys_write,
+    sys_allocate,
+    sys_release,
+    sys_getpid,
+    sys_sleep
+];
+
+/// The caller's registers as `syscall_entry` pushed them, rax went first.
+#[repr(C)]
+#[allow(dead_code)]
+pub struct SyscallFrame {
+    r15 : u64,
+    r14 : u64,
+    r13 : u64,
+    r12 : u64,
+    r11 : u64,
+    r10 : u64,
+    r9 : u64,
+    r8 : u64,
+    rbp : u64,
+    rdi : u64,
+    rsi : u64,
+    rdx : u64,
+    rcx : u64,
+    rbx : u64,
+    rax : u64
+}
+
+fn current_vm_pool() -> &'static mut VMPool {
+    process_table::get_curr_process_table_mut().vm_pool
+}
+
+// anything can come in from a caller, VirtAddr::new would panic on a non canonical address
+fn user_addr(addr : u64) -> Result<VirtAddr, SyscallError> {
+    VirtAddr::try_new(addr).map_err(|_| SyscallError::InvalidArgument)
+}
+
+fn sys_yield(_args : &[u64; 3]) -> Result<u64, SyscallError> {
+    crate::scheduler::_yield();
+    Ok(0)
+}
+
+fn sys_exit(args : &[u64; 3]) -> Result<u64, SyscallError> {
+    process_table::exit(args[0]);
+}
+
+// write(buffer, len) prints UTF-8 text to the console and returns its length
+fn sys_write(args : &[u64; 3]) -> Result<u64, SyscallError> {
+    if args[1] > MAX_WRITE {
+        return Err(SyscallError::InvalidArgument);
+    }
+    // copied first, the caller's memory can change under us or go away
+    let mut buffer = Vec::with_capacity(args[1] as usize);
+    buffer.resize(args[1] as usize, 0u8);
+    user_memory::copy_from_user(&mut buffer, args[0])?;
+    let text = core::str::from_utf8(&buffer).map_err(|_| SyscallError::InvalidArgument)?;
+    print!("{}", text);
+    Ok(args[1])
+}
+
+// allocate(size) returns the start of a new read/write region
+fn sys_allocate(args : &[u64; 3]) -> Result<u64, SyscallError> {
+    if args[0] > crate::machine::HEAP_SIZE {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let addr = current_vm_pool().allocate(args[0] as usize)?;
+    Ok(addr.as_u64())
+}
+
+// release(addr) takes the region starting at addr
+fn sys_release(args : &[u64; 3]) -> Result<u64, SyscallError> {
+    let vm_pool = current_vm_pool();
+    match vm_pool.get_region(user_addr(args[0])?) {
+        Some((start, _)) if start.as_u64() == args[0] && !vm_pool.is_kernel_region(start) => {
+            vm_pool.release(start);
+            Ok(0)
+        },
+        _ => Err(SyscallError::InvalidArgument)
+    }
+}
+
+fn sys_getpid(_args : &[u64; 3]) -> Result<u64, SyscallError> {
+    Ok(u64::from(process_table::get_curr_process_table().process_id))
+}
+
+// sleep(ticks) lets the others run until that many timer ticks went by
+fn sys_sleep(args : &[u64; 3]) -> Result<u64, SyscallError> {
+    crate::scheduler::sleep(args[0]);
+    Ok(0)
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame : &mut SyscallFrame) {
+    let args = [frame.rdi, frame.rsi, frame.rdx];
+    let result = match SYSCALLS.get(frame.rax as usize) {
+        Some(syscall) => syscall(&args),
+        None => Err(SyscallError::NoSuchCall)
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(error) => error.code()
+    };
+}
+
+/// The handler of `int 0x80`. It saves the registers in the order SyscallFrame expects them,
+/// so that the result can be stored over the saved rax.
+#[naked]
+pub extern "C" fn syscall_entry() {
+    unsafe {
+        asm!("push rax
+              push rbx
+              push rcx
+              push rdx
+              push rsi
+              push rdi
+              push rbp
+              push r8
+              push r9
+              push r10
+              push r11
+              push r12
+              push r13
+              push r14
+              push r15
+              mov rdi, rsp
+              call syscall_dispatch
+              pop r15
+              pop r14
+              pop r13
+              pop r12
+              pop r11
+              pop r10
+              pop r9
+              pop r8
+              pop rbp
+              pop rdi
+              pop rsi
+              pop rdx
+              pop rcx
+              pop rbx
+              pop rax
+              iretq"
+        :::: "intel", "volatile");
+    }
+}
+
+// xor edi, edi; mov eax, SYS_EXIT; int 0x80
+const EXIT_STUB : [u8; 9] = [0x31, 0xff, 0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00, 0xcd, 0x80];
+
+/// Maps the code a ring 3 entry function returns into, which exits with 0 the way returning
+/// from a kernel process does. `vm_pool` has to be the VMPool of the loaded address space.
+pub fn map_exit_stub(vm_pool : &mut VMPool) -> Result<VirtAddr, VMError> {
+    let addr = vm_pool.allocate_with(EXIT_STUB.len(), Protection::READ | Protection::WRITE)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(EXIT_STUB.as_ptr(), addr.as_u64() as *mut u8, EXIT_STUB.len());
+    }
+    vm_pool.protect(addr, Protection::READ | Protection::EXECUTE);
+    Ok(addr)
+}
+
+/// Makes system call `number`, from ring 0 as well as from ring 3.
+pub fn syscall(number : u64, arg0 : u64, arg1 : u64, arg2 : u64) -> u64 {
+    let result : u64;
+    unsafe {
+        asm!("int 0x80"
+        : "={rax}"(result)
+        : "{rax}"(number), "{rdi}"(arg0), "{rsi}"(arg1), "{rdx}"(arg2)
+        : "memory"
+        : "intel", "volatile");
+    }
+    result
+}
+
+fn syscall_result(result : u64) -> Result<u64, SyscallError> {
+    match SyscallError::from_result(result) {
+        Some(error) => Err(error),
+        None => Ok(result)
+    }
+}
+
+pub fn yield_now() {
+    syscall(SYS_YIELD, 0, 0, 0);
+}
+
+pub fn exit(code : u64) -> ! {
+    syscall(SYS_EXIT, code, 0, 0);
+    unreachable!();
+}
+
+/// `text` has to be in the caller's VMPool, like anything else handed to the kernel.
+pub fn write(text : &str) -> Result<u64, SyscallError> {
+    syscall_result(syscall(SYS_WRITE, text.as_ptr() as u64, text.len() as u64, 0))
+}
+
+pub fn allocate(size : u64) -> Result<VirtAddr, SyscallError> {
+    syscall_result(syscall(SYS_ALLOCATE, size, 0, 0)).map(VirtAddr::new)
+}
+
+pub fn release(addr : VirtAddr) -> Result<(), SyscallError> {
+    syscall_result(syscall(SYS_RELEASE, addr.as_u64(), 0, 0)).map(|_| ())
+}
+
+pub fn getpid() -> u16 {
+    syscall(SYS_GETPID, 0, 0, 0) as u16
+}
+
+pub fn sleep(ticks : u64) {
+    syscall(SYS_SLEEP, ticks, 0, 0);
+}
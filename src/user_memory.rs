@@ -0,0 +1,98 @@
+use x86_64::VirtAddr;
+use crate::{
+    process_table,
+    syscall::SyscallError,
+    vm_pool::Protection
+};
+
+// user_copy(dst, src, len) copies len bytes and returns how many of them it did not copy.
+// A fault in rep movsb leaves the count of the bytes left in rcx, so the exception table
+// only has to point the faulting copy at the instruction after it.
+global_asm!("
+    .intel_syntax noprefix
+    .global user_copy
+    .global user_copy_access
+    .global user_copy_fixup
+user_copy:
+    cld
+    mov rcx, rdx
+user_copy_access:
+    rep movsb
+user_copy_fixup:
+    mov rax, rcx
+    ret
+    .att_syntax prefix
+");
+
+extern "C" {
+    fn user_copy(dst : *mut u8, src : *const u8, len : u64) -> u64;
+    fn user_copy_access();
+    fn user_copy_fixup();
+}
+
+// an instruction that may fault on a user address, and where to go on instead if it does
+struct ExceptionTableEntry {
+    instruction : unsafe extern "C" fn(),
+    fixup : unsafe extern "C" fn()
+}
+
+static EXCEPTION_TABLE : [ExceptionTableEntry; 1] = [
+    ExceptionTableEntry { instruction : user_copy_access, fixup : user_copy_fixup }
+];
+
+/// Where kernel code faulting at `instruction` recovers, if it touches user memory on
+/// purpose. A fault anywhere else in the kernel is a bug.
+pub fn fixup_address(instruction : VirtAddr) -> Option<VirtAddr> {
+    EXCEPTION_TABLE.iter()
+        .find(|entry| entry.instruction as u64 == instruction.as_u64())
+        .map(|entry| VirtAddr::new(entry.fixup as u64))
+}
+
+/// Checks that [addr, addr + len) is memory of the current process' VMPool that it may
+/// access with `prot`.
+pub fn check_user_range(addr : u64, len : u64, prot : Protection) -> Result<VirtAddr, SyscallError> {
+    let start = VirtAddr::try_new(addr).map_err(|_| SyscallError::BadAddress)?;
+    let end = addr.checked_add(len).ok_or(SyscallError::BadAddress)?;
+    let vm_pool = &process_table::get_curr_process_table().vm_pool;
+    let mut page = addr & !(crate::machine::PAGE_SIZE - 1);
+    while page < end {
+        let page_addr = VirtAddr::try_new(page).map_err(|_| SyscallError::BadAddress)?;
+        if !vm_pool.is_legitimate(page_addr) {
+            return Err(SyscallError::BadAddress);
+        }
+        // a ring 3 process's kernel stack lies in its VMPool as well
+        if vm_pool.is_kernel_region(page_addr) {
+            return Err(SyscallError::BadAddress);
+        }
+        match vm_pool.get_protection(page_addr) {
+            Some(region_prot) if region_prot.contains(prot) => {},
+            _ => return Err(SyscallError::BadAddress)
+        }
+        page += crate::machine::PAGE_SIZE;
+    }
+    Ok(start)
+}
+
+/// Copies `len` bytes from `src` to `dst` and returns how many were left when it faulted,
+/// 0 if none. Neither address is checked, a fault on either just ends the copy.
+pub unsafe fn copy_unchecked(dst : *mut u8, src : *const u8, len : u64) -> u64 {
+    user_copy(dst, src, len)
+}
+
+/// Fills `dst` from the caller's memory at `src`.
+pub fn copy_from_user(dst : &mut [u8], src : u64) -> Result<(), SyscallError> {
+    check_user_range(src, dst.len() as u64, Protection::READ)?;
+    match unsafe { copy_unchecked(dst.as_mut_ptr(), src as *const u8, dst.len() as u64) } {
+        0 => Ok(()),
+        _ => Err(SyscallError::BadAddress)
+    }
+}
+
+/// Copies `src` to the caller's memory at `dst`.
+pub fn copy_to_user(dst : u64, src : &[u8]) -> Result<(), SyscallError> {
+    check_user_range(dst, src.len() as u64, Protection::READ | Protection::WRITE)?;
+    match unsafe { copy_unchecked(dst as *mut u8, src.as_ptr(), src.len() as u64) } {
+        0 => Ok(()),
+        _ => Err(SyscallError::BadAddress)
+    }
+}
@@ -1,14 +1,67 @@
 use crate::{
-    process_table::MyProcess
+    process_table::MyProcess,
+    shared_memory::{self, SharedHandle}
 };
 use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags as Flags;
 use core::fmt::{Formatter, Error};
+use core::ops::BitOr;
+use alloc::vec::Vec;
+
+/// What a VMPool region may be used for. A region without READ is never mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Protection(u64);
+
+#[allow(dead_code)]
+impl Protection {
+    pub const NONE : Protection = Protection(0);
+    pub const READ : Protection = Protection(1 << 0);
+    pub const WRITE : Protection = Protection(1 << 1);
+    pub const EXECUTE : Protection = Protection(1 << 2);
+    pub const USER : Protection = Protection(1 << 3);
+
+    pub fn contains(& self, other : Protection) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The flags a page of a region with this protection is mapped with. Without READ the
+    /// page is not present, it keeps its frame for when the region is readable again.
+    pub fn page_flags(& self) -> Flags {
+        let mut flags = Flags::empty();
+        if self.contains(Protection::READ) {
+            flags.insert(Flags::PRESENT);
+        }
+        if self.contains(Protection::WRITE) {
+            flags.insert(Flags::WRITABLE);
+        }
+        if !self.contains(Protection::EXECUTE) {
+            flags.insert(Flags::NO_EXECUTE);
+        }
+        if self.contains(Protection::USER) {
+            flags.insert(Flags::USER_ACCESSIBLE);
+        }
+        flags
+    }
+}
+
+impl BitOr for Protection {
+    type Output = Protection;
+
+    fn bitor(self, other : Protection) -> Protection {
+        Protection(self.0 | other.0)
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 struct VMPoolEntry {
     start : VirtAddr,
-    size : u64 // number of pages it spans
+    size : u64, // number of pages it spans
+    prot : Protection,
+    shared : Option<SharedHandle>, // the object the region maps, if any
+    offset : u64, // page of the shared object the region starts at
+    guard : bool // the unmapped space below a stack, which it can grow into
 }
 
 impl VMPoolEntry {
@@ -20,32 +73,140 @@ impl VMPoolEntry {
         addr >= self.start && addr < self.end()
     }
 
-    fn update_val(&mut self, start : VirtAddr, size : u64) {
-        self.start = start;
-        self.size = size;
+    // the part of the region starting `skip` pages in and spanning `size` pages
+    fn piece(& self, skip : u64, size : u64) -> VMPoolEntry {
+        VMPoolEntry {
+            start : self.start + skip * crate::machine::PAGE_SIZE,
+            size,
+            prot : self.prot,
+            shared : self.shared,
+            offset : self.offset + skip,
+            guard : self.guard
+        }
     }
+}
 
-    fn free_entry(&mut self) {
-        self.update_val(VirtAddr::new(0), 0 as u64);
-    }
+/// Why a VMPool request could not be satisfied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VMError {
+    InvalidSize,
+    OutOfSpace,
+    Unaligned,
+    NoRegion,
+    InvalidHandle
 }
 
-//#[derive(Debug)]
-#[repr(C)]
+/// The regions of a process' address space, kept on the kernel heap ordered by start
+/// address. The table grows as long as the heap has room for it, a region that doesn't
+/// fit any more is refused with OutOfSpace instead of taking the kernel down.
 pub struct VMPool {
     start_addr: VirtAddr,
     pool_size : u64,
-    entries : [VMPoolEntry; 254]
+    entries : Vec<VMPoolEntry>,
+    heap_start : VirtAddr, // 0 until the program break is first used
+    brk : VirtAddr,
+    user : bool // every region is accessible from ring 3
 }
 
 impl VMPool {
-    pub fn new(&mut self,
-        _base_addr : u64,
-        _size : u64/*,
-        _page_table : *mut ProcessTable*/) {
-        self.start_addr = VirtAddr::new(_base_addr);
-        self.pool_size = _size >> crate::machine::PAGE_OFFSET_BITS;
-        self.entries = [VMPoolEntry{start:VirtAddr::new(0), size:0}; 254];
+    pub fn new(_base_addr : u64, _size : u64) -> VMPool {
+        VMPool {
+            start_addr : VirtAddr::new(_base_addr),
+            pool_size : _size >> crate::machine::PAGE_OFFSET_BITS,
+            entries : Vec::new(),
+            heap_start : VirtAddr::new(0),
+            brk : VirtAddr::new(0),
+            user : false
+        }
+    }
+
+    /// Makes the pool's regions accessible from ring 3, including the ones it already has.
+    /// Has to be done before any of them is mapped.
+    pub fn set_user_mode(&mut self) {
+        self.user = true;
+        for entry in self.entries.iter_mut() {
+            entry.prot = entry.prot | Protection::USER;
+        }
+    }
+
+    pub fn is_user_mode(& self) -> bool {
+        self.user
+    }
+
+    // the protection a region asking for `prot` gets in this pool
+    fn region_protection(& self, prot : Protection) -> Protection {
+        if self.user { prot | Protection::USER } else { prot }
+    }
+
+    pub fn copy_from(&mut self, other : & VMPool) {
+        self.start_addr = other.start_addr;
+        self.pool_size = other.pool_size;
+        self.entries = other.entries.clone();
+        for handle in self.entries.iter().filter_map(|entry| entry.shared) {
+            shared_memory::get(handle);
+        }
+        self.heap_start = other.heap_start;
+        self.brk = other.brk;
+        self.user = other.user;
+    }
+
+    // index of the first region starting after `addr`
+    fn index_after(& self, addr : VirtAddr) -> usize {
+        match self.entries.binary_search_by_key(&addr, |entry| entry.start) {
+            Ok(index) => index + 1,
+            Err(index) => index
+        }
+    }
+
+    fn find_entry(& self, addr : VirtAddr) -> Option<&VMPoolEntry> {
+        match self.index_after(addr) {
+            0 => None,
+            index => Some(&self.entries[index - 1]).filter(|entry| entry.contained(addr))
+        }
+    }
+
+    // the region starting exactly at `addr`
+    fn entry_mut(&mut self, addr : VirtAddr) -> Option<&mut VMPoolEntry> {
+        match self.entries.binary_search_by_key(&addr, |entry| entry.start) {
+            Ok(index) => Some(&mut self.entries[index]),
+            Err(_) => None
+        }
+    }
+
+    // Room for `count` more regions. The table is the only thing here that needs the kernel
+    // heap, so this is where a full heap turns into an error.
+    fn reserve(&mut self, count : usize) -> Result<(), VMError> {
+        self.entries.try_reserve(count).map_err(|_| VMError::OutOfSpace)
+    }
+
+    fn insert_entry(&mut self, entry : VMPoolEntry) -> Result<(), VMError> {
+        self.reserve(1)?;
+        let index = self.index_after(entry.start);
+        self.entries.insert(index, entry);
+        Ok(())
+    }
+
+    /// Start and end of the region containing `addr`.
+    pub fn get_region(& self, addr : VirtAddr) -> Option<(VirtAddr, VirtAddr)> {
+        self.find_entry(addr).map(|entry| (entry.start, entry.end()))
+    }
+
+    /// The shared object and page in it that `addr` maps, if it is in a shared region.
+    pub fn get_shared_page(& self, addr : VirtAddr) -> Option<(SharedHandle, u64)> {
+        let entry = self.find_entry(addr)?;
+        let handle = entry.shared?;
+        let page = (addr.as_u64() - entry.start.as_u64()) >> crate::machine::PAGE_OFFSET_BITS;
+        Some((handle, entry.offset + page))
+    }
+
+    /// Protection of the region containing `addr`.
+    pub fn get_protection(& self, addr : VirtAddr) -> Option<Protection> {
+        self.find_entry(addr).map(|entry| entry.prot)
+    }
+
+    /// Number of regions in the pool.
+    pub fn region_count(& self) -> usize {
+        self.entries.len()
     }
 
     fn end_addr(& self) -> VirtAddr {
@@ -57,62 +218,344 @@ impl VMPool {
     }
 
     pub fn is_legitimate(& self, addr: VirtAddr) -> bool {
-        if !self.contained(addr) {
-            return false;
+        self.contained(addr) && self.find_entry(addr).is_some()
+    }
+
+    pub fn allocate(&mut self, size : usize) -> Result<VirtAddr, VMError> {
+        self.allocate_with(size, Protection::READ | Protection::WRITE)
+    }
+
+    pub fn allocate_with(&mut self, size : usize, prot : Protection) -> Result<VirtAddr, VMError> {
+        let start = self.start_addr;
+        self.allocate_from(start, size, prot)
+    }
+
+    /// Allocates the region at `hint` if that range is free, otherwise at the first hole
+    /// after it, and only then anywhere in the pool.
+    pub fn allocate_at(&mut self, hint : VirtAddr, size : usize, prot : Protection) -> Result<VirtAddr, VMError> {
+        let hint = hint.align_up(crate::machine::PAGE_SIZE);
+        if !self.contained(hint) {
+            return self.allocate_with(size, prot);
+        }
+        match self.allocate_from(hint, size, prot) {
+            Err(VMError::OutOfSpace) => self.allocate_with(size, prot),
+            result => result
         }
+    }
+
+    // First fit: the free space is whatever lies between the regions, so a released region
+    // is a hole again right away and merges with the holes next to it.
+    fn allocate_from(&mut self, from : VirtAddr, size : usize, prot : Protection) -> Result<VirtAddr, VMError> {
+        let size = (size as u64).checked_add(crate::machine::PAGE_SIZE - 1)
+            .ok_or(VMError::InvalidSize)? >> crate::machine::PAGE_OFFSET_BITS;
+        if size == 0 || size > self.pool_size {
+            return Err(VMError::InvalidSize);
+        }
+        let prot = self.region_protection(prot);
+
+        let mut min_free = from;
         for entry in self.entries.iter() {
-            if entry.start.as_u64() == 0 {
+            if entry.end() <= min_free {
                 continue;
-            } else {
-                if entry.contained(addr) {
-                    return true;
-                }
             }
+            if entry.start >= min_free + size * crate::machine::PAGE_SIZE {
+                break;
+            }
+            min_free = entry.end();
         }
-        false
+        if min_free + (size * crate::machine::PAGE_SIZE) > self.end_addr() {
+            return Err(VMError::OutOfSpace);
+        }
+
+        self.insert_entry(VMPoolEntry { start : min_free, size, prot, shared : None, offset : 0, guard : false })?;
+        Ok(min_free)
     }
 
-    pub fn allocate(&mut self, size : usize) -> Option<VirtAddr> {
-        let mut size = size as u64 + crate::machine::PAGE_SIZE - 1;
-        size >>= crate::machine::PAGE_OFFSET_BITS;
-        let mut min_free = self.start_addr;
-        for entry in self.entries.iter() {
-            if entry.start.as_u64() == 0 {
-                continue;
-            } else {
-                min_free = if min_free < entry.end() {
-                    entry.end()
-                } else {
-                    min_free
-                };
+    /// Allocates a stack of `size` bytes that may grow down to `limit` bytes, and returns its
+    /// top. The space it can grow into is reserved as a guard region, whose lowest page is
+    /// never mapped.
+    pub fn allocate_stack(&mut self, size : usize, limit : usize) -> Result<VirtAddr, VMError> {
+        let limit = core::cmp::max(size, limit) as u64;
+        // the pages of the stack at its limit and the guard page below them
+        let reserved = limit.checked_add(2 * crate::machine::PAGE_SIZE - 1)
+            .ok_or(VMError::InvalidSize)? & !(crate::machine::PAGE_SIZE - 1);
+        // the guard and the stack, so the stack can't fail once the guard is in
+        self.reserve(2)?;
+        let start = self.allocate_with(reserved as usize, Protection::NONE)?;
+        let top = start + reserved;
+
+        let stack_pages = (size as u64 + crate::machine::PAGE_SIZE - 1) >> crate::machine::PAGE_OFFSET_BITS;
+        let guard = self.entry_mut(start).unwrap();
+        guard.size -= stack_pages;
+        guard.guard = true;
+        let stack = guard.piece(guard.size, stack_pages);
+        let prot = self.region_protection(Protection::READ | Protection::WRITE);
+        self.insert_entry(VMPoolEntry {
+            prot,
+            guard : false,
+            ..stack
+        })?;
+        Ok(top)
+    }
+
+    /// Allocates a stack only the kernel may use, of `size` bytes and with a guard page below
+    /// it, and returns its top. It never grows, running into the guard page is an overflow.
+    pub fn allocate_kernel_stack(&mut self, size : usize) -> Result<VirtAddr, VMError> {
+        let top = self.allocate_stack(size, size)?;
+        let stack = self.find_entry(top - 1u64).unwrap().start;
+        let guard = self.find_entry(stack - 1u64).unwrap().start;
+        // a ring 3 pool made both of them USER
+        for addr in [stack, guard].iter() {
+            let entry = self.entry_mut(*addr).unwrap();
+            entry.prot = Protection(entry.prot.0 & !Protection::USER.0);
+        }
+        Ok(top)
+    }
+
+    /// Whether the region containing `addr` belongs to the kernel rather than to the ring 3
+    /// code of the process, like its kernel stack.
+    pub fn is_kernel_region(& self, addr : VirtAddr) -> bool {
+        self.user && self.find_entry(addr).map_or(false, |entry| !entry.prot.contains(Protection::USER))
+    }
+
+    pub fn is_guard(& self, addr : VirtAddr) -> bool {
+        self.find_entry(addr).map_or(false, |entry| entry.guard)
+    }
+
+    /// Grows the stack above the guard region containing `addr` down to the page of `addr`.
+    /// Fails when that would leave no guard page. It runs in the page fault handler, maybe
+    /// with the kernel heap locked, so the two regions are only adjusted where they are.
+    pub fn grow_stack(&mut self, addr : VirtAddr) -> bool {
+        let index = match self.index_after(addr) {
+            0 => return false,
+            index => index - 1
+        };
+        let guard = self.entries[index];
+        if !guard.guard || !guard.contained(addr) {
+            return false;
+        }
+        let new_start = addr.align_down(crate::machine::PAGE_SIZE);
+        if new_start < guard.start + crate::machine::PAGE_SIZE {
+            return false;
+        }
+        match self.entries.get(index + 1) {
+            Some(stack) if stack.start == guard.end() => {},
+            _ => return false
+        }
+
+        // the order stays the same, the stack only moves its start into the guard
+        let added = (guard.end().as_u64() - new_start.as_u64()) >> crate::machine::PAGE_OFFSET_BITS;
+        self.entries[index].size -= added;
+        let stack = &mut self.entries[index + 1];
+        stack.start = new_start;
+        stack.size += added;
+        true
+    }
+
+    /// Maps all of a shared memory object into a new region. The pages are backed by the
+    /// object's frames, so every process mapping it sees the same memory.
+    pub fn map_shared(&mut self, handle : SharedHandle, prot : Protection) -> Result<VirtAddr, VMError> {
+        let start = self.start_addr;
+        self.map_shared_at(start, handle, prot)
+    }
+
+    /// Like `map_shared`, with the region placed like `allocate_at` places it. A ring 3 program
+    /// gets its arguments before its VMPool exists, so memory shared with it has to go to an
+    /// address picked before `spawn_elf`.
+    pub fn map_shared_at(&mut self, hint : VirtAddr, handle : SharedHandle, prot : Protection) -> Result<VirtAddr, VMError> {
+        let pages = match shared_memory::page_count(handle) {
+            Some(pages) => pages,
+            None => return Err(VMError::InvalidHandle)
+        };
+        let addr = self.allocate_at(hint, (pages * crate::machine::PAGE_SIZE) as usize, prot)?;
+        shared_memory::get(handle);
+        self.entry_mut(addr).unwrap().shared = Some(handle);
+        Ok(addr)
+    }
+
+    /// Changes the protection of the region starting at `addr`, pages that are already
+    /// mapped are updated in place. Returns false if there is no such region.
+    pub fn protect(&mut self, addr : VirtAddr, prot : Protection) -> bool {
+        let prot = self.region_protection(prot);
+        match self.entry_mut(addr) {
+            Some(entry) => {
+                entry.prot = prot;
+                for i in 0..entry.size {
+                    let page_addr = entry.start + i * crate::machine::PAGE_SIZE;
+                    MyProcess::protect_page(page_addr, prot);
+                }
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Grows or shrinks the region starting at `addr` to `new_size` bytes. It only grows in
+    /// place, so the address space after it has to be free. Shrinking releases the tail pages.
+    pub fn resize(&mut self, addr : VirtAddr, new_size : usize) -> Result<(), VMError> {
+        let entry = match self.entry_mut(addr) {
+            Some(entry) => *entry,
+            None => return Err(VMError::NoRegion)
+        };
+        let pages = (new_size as u64).checked_add(crate::machine::PAGE_SIZE - 1)
+            .ok_or(VMError::InvalidSize)? >> crate::machine::PAGE_OFFSET_BITS;
+        if pages == 0 {
+            return Err(VMError::InvalidSize);
+        }
+
+        if pages > entry.size && entry.shared.is_some() {
+            return Err(VMError::InvalidSize);
+        }
+        if pages < entry.size {
+            let tail = entry.start + pages * crate::machine::PAGE_SIZE;
+            return self.release_range(tail, (entry.size - pages) * crate::machine::PAGE_SIZE);
+        }
+
+        // checked against the pool before it becomes an address, past it could be non canonical
+        let room = (self.end_addr().as_u64() - entry.start.as_u64()) >> crate::machine::PAGE_OFFSET_BITS;
+        if pages > room {
+            return Err(VMError::OutOfSpace);
+        }
+        let new_end = entry.start + pages * crate::machine::PAGE_SIZE;
+        if let Some(next) = self.entries.get(self.index_after(entry.start)) {
+            if next.start < new_end {
+                return Err(VMError::OutOfSpace);
             }
+        }
+        self.entry_mut(addr).unwrap().size = pages;
+        Ok(())
+    }
+
+    // the heap region is placed at the first hole the first time the break is used,
+    // it always keeps at least one page so its place stays reserved
+    fn heap_start(&mut self) -> Result<VirtAddr, VMError> {
+        if self.heap_start.as_u64() == 0 {
+            let start = self.allocate(crate::machine::PAGE_SIZE as usize)?;
+            self.heap_start = start;
+            self.brk = start;
+        }
+        Ok(self.heap_start)
+    }
+
+    /// The current program break, the end of the process heap.
+    pub fn program_break(&mut self) -> Result<VirtAddr, VMError> {
+        self.heap_start()?;
+        Ok(self.brk)
+    }
+
+    /// Moves the program break to `new_break`, growing or shrinking the heap region with it.
+    pub fn brk(&mut self, new_break : VirtAddr) -> Result<VirtAddr, VMError> {
+        let heap_start = self.heap_start()?;
+        if new_break < heap_start {
+            return Err(VMError::InvalidSize);
+        }
+        if new_break > self.end_addr() {
+            return Err(VMError::OutOfSpace);
+        }
+        let size = core::cmp::max(new_break.as_u64() - heap_start.as_u64(), crate::machine::PAGE_SIZE);
+        self.resize(heap_start, size as usize)?;
+        self.brk = new_break;
+        Ok(new_break)
+    }
+
+    /// Moves the program break by `increment` bytes and returns the old break, so a grown
+    /// heap's new memory starts at the returned address.
+    pub fn sbrk(&mut self, increment : i64) -> Result<VirtAddr, VMError> {
+        let old_break = self.program_break()?;
+        // anything past the pool could be a non canonical address, it has to stop here
+        let new_break = match (old_break.as_u64() as i64).checked_add(increment) {
+            Some(new_break) if new_break >= 0 && new_break as u64 <= self.end_addr().as_u64() => new_break as u64,
+            _ => return Err(VMError::InvalidSize)
+        };
+        let new_break = VirtAddr::try_new(new_break).map_err(|_| VMError::InvalidSize)?;
+        self.brk(new_break)?;
+        Ok(old_break)
+    }
+
+    /// Releases the whole region starting at `addr`.
+    pub fn release(&mut self, addr: VirtAddr) {
+        let size = match self.entry_mut(addr) {
+            Some(entry) => entry.size * crate::machine::PAGE_SIZE,
+            None => return
+        };
+        let _ = self.release_range(addr, size);
+    }
+
+    /// Releases every page in [addr, addr + size), like munmap. Regions that are only
+    /// partly covered are trimmed, or split in two when the range is in their middle.
+    /// Parts of the range that belong to no region are skipped.
+    pub fn release_range(&mut self, addr : VirtAddr, size : u64) -> Result<(), VMError> {
+        if addr.as_u64() % crate::machine::PAGE_SIZE != 0 || size % crate::machine::PAGE_SIZE != 0 {
+            return Err(VMError::Unaligned);
+        }
+        if size == 0 {
+            return Err(VMError::InvalidSize);
+        }
+        let end = addr + size;
 
-            if !self.contained(min_free+(size*crate::machine::PAGE_SIZE)) {
-                return None;
+        // the regions in the range follow each other in the table, only the first one may
+        // start before it and only the last one may end after it
+        let first = match self.index_after(addr) {
+            0 => 0,
+            index if self.entries[index - 1].end() > addr => index - 1,
+            index => index
+        };
+        let last = first + self.entries[first..].iter().take_while(|entry| entry.start < end).count();
+        if first == last {
+            return Ok(());
+        }
+
+        let mut pieces = [None, None];
+        let head = self.entries[first];
+        if head.start < addr {
+            let pages = (addr.as_u64() - head.start.as_u64()) / crate::machine::PAGE_SIZE;
+            pieces[0] = Some(head.piece(0, pages));
+        }
+        let tail = self.entries[last - 1];
+        if tail.end() > end {
+            let skip = (end.as_u64() - tail.start.as_u64()) / crate::machine::PAGE_SIZE;
+            pieces[1] = Some(tail.piece(skip, tail.size - skip));
+        }
+        // a region split in two is the only way the table grows here
+        if pieces.iter().flatten().count() > last - first {
+            self.reserve(1)?;
+        }
+        // every piece of a shared region holds its own reference to the object, taken before
+        // the region's own is dropped
+        for piece in pieces.iter().flatten() {
+            if let Some(handle) = piece.shared {
+                shared_memory::get(handle);
             }
         }
-        for entry in self.entries.iter_mut() {
-            if entry.start.as_u64() == 0 {
-                entry.update_val(min_free, size);
-                break;
+        for entry in self.entries[first..last].iter() {
+            let free_start = core::cmp::max(entry.start, addr);
+            let free_end = core::cmp::min(entry.end(), end);
+            VMPool::free_pages(free_start, free_end);
+            if let Some(handle) = entry.shared {
+                shared_memory::put(handle);
             }
         }
-        Some(min_free)
+        self.entries.splice(first..last, pieces.iter().filter_map(|piece| *piece));
+        Ok(())
     }
 
-    pub fn release(&mut self, addr: VirtAddr) {
-        for entry in self.entries.iter_mut() {
-            if entry.start.as_u64() == 0 {
-                continue;
-            } else if entry.start == addr {
-                for i in 0..entry.size {
-                    let mut free_addr : u64 = entry.start.as_u64();
-                    free_addr += i*crate::machine::PAGE_SIZE;
-                    MyProcess::free_page(VirtAddr::new(free_addr));
-                }
-                entry.free_entry();
-                break;
+    // hands back the frames of the mapped pages in [start, end)
+    fn free_pages(start : VirtAddr, end : VirtAddr) {
+        let mut page_addr = start;
+        while page_addr < end {
+            if MyProcess::is_mapped(page_addr) {
+                MyProcess::free_page(page_addr);
             }
+            page_addr += crate::machine::PAGE_SIZE;
+        }
+    }
+}
+
+// the pages are unmapped with the address space, only the references to shared objects are left
+impl Drop for VMPool {
+    fn drop(&mut self) {
+        for handle in self.entries.iter().filter_map(|entry| entry.shared) {
+            shared_memory::put(handle);
         }
     }
 }